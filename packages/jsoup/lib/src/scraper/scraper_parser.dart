@@ -9,6 +9,15 @@ import 'package:scraper/scraper.dart';
 /// Uses html5ever (Servo) for parsing and CSS selector evaluation via a
 /// compiled Rust `scraper_bridge` dynamic library loaded as a CodeAsset.
 class ScraperParser implements NativeHtmlParser {
+  /// How many compiled selectors [_compiledSelectors] keeps before evicting the
+  /// least recently used.
+  static const int _maxCompiledSelectors = 256;
+
+  /// Compiled selector handles keyed by selector string, least recently used
+  /// first. Sources run the same selectors over and over, so each is parsed once
+  /// while it stays in the cache and its handle stays live.
+  final Map<String, int> _compiledSelectors = <String, int>{};
+
   // -- String helpers --
 
   static Pointer<Char> _toNative(String s) => s.toNativeUtf8(allocator: calloc).cast<Char>();
@@ -62,21 +71,31 @@ class ScraperParser implements NativeHtmlParser {
 
   // -- CSS Selectors --
 
-  @override
-  int select(int handle, String selector) {
+  int _compileSelector(String selector) {
+    final int? cached = _compiledSelectors.remove(selector);
+    // The handle can die behind the cache, e.g. when a session holding it ends.
+    if (cached != null && scraper_handle_status(cached) == 1) {
+      _compiledSelectors[selector] = cached;
+      return cached;
+    }
     final Pointer<Char> nSel = _toNative(selector);
-    final int result = scraper_select(handle, nSel);
+    final int result = scraper_compile_selector(nSel);
     calloc.free(nSel);
+    // Failures are not cached, so each retry reports its parse error again.
+    if (result < 0) return result;
+    if (_compiledSelectors.length >= _maxCompiledSelectors) {
+      final String oldest = _compiledSelectors.keys.first;
+      scraper_free(_compiledSelectors.remove(oldest)!);
+    }
+    _compiledSelectors[selector] = result;
     return result;
   }
 
   @override
-  int selectFirst(int handle, String selector) {
-    final Pointer<Char> nSel = _toNative(selector);
-    final int result = scraper_select_first(handle, nSel);
-    calloc.free(nSel);
-    return result;
-  }
+  int select(int handle, String selector) => scraper_select_compiled(handle, _compileSelector(selector));
+
+  @override
+  int selectFirst(int handle, String selector) => scraper_select_first_compiled(handle, _compileSelector(selector));
 
   // -- Attributes --
 
@@ -341,8 +360,23 @@ class ScraperParser implements NativeHtmlParser {
   void free(int handle) => scraper_free(handle);
 
   @override
-  void releaseAll() => scraper_release_all();
+  void releaseAll() {
+    _freeCompiledSelectors();
+    scraper_release_all();
+  }
 
   @override
-  void dispose() => scraper_dispose();
+  void dispose() {
+    _freeCompiledSelectors();
+    scraper_dispose();
+  }
+
+  // With a shared store, releasing only ends this isolate's session, so the cached
+  // selectors are freed here rather than left to it.
+  void _freeCompiledSelectors() {
+    for (final int handle in _compiledSelectors.values) {
+      if (scraper_handle_status(handle) == 1) scraper_free(handle);
+    }
+    _compiledSelectors.clear();
+  }
 }
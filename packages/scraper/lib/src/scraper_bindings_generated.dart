@@ -18,6 +18,12 @@ external void scraper_free_handle_array(
   int _len,
 );
 
+@ffi.Native<ffi.Void Function(ffi.Pointer<ffi.Pointer<ffi.Char>>, ffi.Int)>()
+external void scraper_free_string_array(
+  ffi.Pointer<ffi.Pointer<ffi.Char>> arr,
+  int len,
+);
+
 @ffi.Native<ffi.Int64 Function(ffi.Pointer<ffi.Char>, ffi.Pointer<ffi.Char>)>()
 external int scraper_parse(
   ffi.Pointer<ffi.Char> html,
@@ -42,6 +48,132 @@ external int scraper_select_first(
   ffi.Pointer<ffi.Char> selector,
 );
 
+/// Parse a selector once for repeated use with `scraper_select_compiled`.
+/// Returns a handle freed with `scraper_free`, or -1 if the selector is invalid.
+@ffi.Native<ffi.Int64 Function(ffi.Pointer<ffi.Char>)>()
+external int scraper_compile_selector(
+  ffi.Pointer<ffi.Char> selector,
+);
+
+/// Like `scraper_compile_selector`, but the :contains family compares text under
+/// `mode` on every document: 0 as Jsoup does, 1 after NFKC and case folding, 2 also
+/// folding katakana to hiragana. Returns -1 if the selector or mode is invalid.
+@ffi.Native<ffi.Int64 Function(ffi.Pointer<ffi.Char>, ffi.Int)>()
+external int scraper_compile_selector_normalized(
+  ffi.Pointer<ffi.Char> selector,
+  int mode,
+);
+
+/// Set how selects compare text in the :contains family on a document, or on the
+/// document a node belongs to. Modes are as for `scraper_compile_selector_normalized`.
+/// Returns 0, or -1 for an invalid handle or mode.
+@ffi.Native<ffi.Int Function(ffi.Int64, ffi.Int)>()
+external int scraper_set_text_normalization(
+  int handle,
+  int mode,
+);
+
+@ffi.Native<ffi.Int64 Function(ffi.Int64, ffi.Int64)>()
+external int scraper_select_compiled(
+  int handle,
+  int selector,
+);
+
+@ffi.Native<ffi.Int64 Function(ffi.Int64, ffi.Int64)>()
+external int scraper_select_first_compiled(
+  int handle,
+  int selector,
+);
+
+/// Select and read the results in one call: attribute values for a selector ending in
+/// `::attr(name)`, text for `::text`, and outer HTML for plain elements. The array is
+/// freed with `scraper_free_string_array`. Returns the number of values, or -1 if the
+/// selector is invalid or the handle unknown.
+@ffi.Native<ffi.Int Function(ffi.Int64, ffi.Pointer<ffi.Char>, ffi.Pointer<ffi.Pointer<ffi.Pointer<ffi.Char>>>, ffi.Pointer<ffi.Int>)>()
+external int scraper_select_values(
+  int handle,
+  ffi.Pointer<ffi.Char> selector,
+  ffi.Pointer<ffi.Pointer<ffi.Pointer<ffi.Char>>> out_values,
+  ffi.Pointer<ffi.Int> out_len,
+);
+
+@ffi.Native<ffi.Int Function(ffi.Int64, ffi.Int64, ffi.Pointer<ffi.Pointer<ffi.Pointer<ffi.Char>>>, ffi.Pointer<ffi.Int>)>()
+external int scraper_select_values_compiled(
+  int handle,
+  int selector,
+  ffi.Pointer<ffi.Pointer<ffi.Pointer<ffi.Char>>> out_values,
+  ffi.Pointer<ffi.Int> out_len,
+);
+
+/// Open a cursor over what `selector` matches under a document or element handle,
+/// finding results one at a time as `scraper_cursor_next` asks for them. The first
+/// `offset` results are skipped and at most `limit` are returned (no limit if
+/// negative). Returns a handle freed with `scraper_free`, or -1 if the selector is
+/// invalid or the handle unknown.
+@ffi.Native<ffi.Int64 Function(ffi.Int64, ffi.Pointer<ffi.Char>, ffi.Int, ffi.Int)>()
+external int scraper_select_cursor(
+  int handle,
+  ffi.Pointer<ffi.Char> selector,
+  int offset,
+  int limit,
+);
+
+@ffi.Native<ffi.Int64 Function(ffi.Int64, ffi.Int64, ffi.Int, ffi.Int)>()
+external int scraper_select_cursor_compiled(
+  int handle,
+  int selector,
+  int offset,
+  int limit,
+);
+
+/// The cursor's next result as a node handle, or -1 once it is exhausted.
+@ffi.Native<ffi.Int64 Function(ffi.Int64)>()
+external int scraper_cursor_next(
+  int cursor,
+);
+
+/// Move the cursor past up to `count` results without creating handles for them.
+/// Returns how many it moved past, or -1 for an unknown cursor.
+@ffi.Native<ffi.Int Function(ffi.Int64, ffi.Int)>()
+external int scraper_cursor_skip(
+  int cursor,
+  int count,
+);
+
+/// Evaluate an XPath 1.0 expression from a document or node handle. Returns a node list
+/// of the selected nodes in document order, or -1 if the expression is invalid or does
+/// not select nodes. Text results are text nodes; attribute results are attribute nodes
+/// (see `scraper_is_attribute_node`).
+@ffi.Native<ffi.Int64 Function(ffi.Int64, ffi.Pointer<ffi.Char>)>()
+external int scraper_select_xpath(
+  int handle,
+  ffi.Pointer<ffi.Char> expr,
+);
+
+/// Message of the last selector parse failure on this thread, or null if the last
+/// selector given to `scraper_select*`/`scraper_compile_selector` parsed. XPath
+/// expressions given to `scraper_select_xpath` are reported the same way.
+@ffi.Native<ffi.Pointer<ffi.Char> Function()>()
+external ffi.Pointer<ffi.Char> scraper_selector_error_message();
+
+/// The part of the selector the last parse failure points at, or null.
+@ffi.Native<ffi.Pointer<ffi.Char> Function()>()
+external ffi.Pointer<ffi.Char> scraper_selector_error_token();
+
+/// Byte offset of the failing token in the selector string, or -1.
+@ffi.Native<ffi.Int64 Function()>()
+external int scraper_selector_error_offset();
+
+/// Why the last handle lookup on this thread failed, or 0 if it succeeded: 1 for a
+/// handle never handed out, 2 for a handle of the wrong kind (a node list given to
+/// `scraper_attr`), 3 for a freed handle, 4 for a handle freed with its document.
+@ffi.Native<ffi.Int Function()>()
+external int scraper_handle_error();
+
+/// Message of the last failed handle lookup on this thread, or null if it succeeded.
+@ffi.Native<ffi.Pointer<ffi.Char> Function()>()
+external ffi.Pointer<ffi.Char> scraper_handle_error_message();
+
 @ffi.Native<ffi.Pointer<ffi.Char> Function(ffi.Int64, ffi.Pointer<ffi.Char>)>()
 external ffi.Pointer<ffi.Char> scraper_attr(
   int handle,
@@ -206,6 +338,22 @@ external int scraper_text_node_is_blank(
   int handle,
 );
 
+@ffi.Native<ffi.Int Function(ffi.Int64)>()
+external int scraper_is_attribute_node(
+  int handle,
+);
+
+@ffi.Native<ffi.Pointer<ffi.Char> Function(ffi.Int64)>()
+external ffi.Pointer<ffi.Char> scraper_attribute_node_name(
+  int handle,
+);
+
+/// The attribute's current value, or null if it has since been removed.
+@ffi.Native<ffi.Pointer<ffi.Char> Function(ffi.Int64)>()
+external ffi.Pointer<ffi.Char> scraper_attribute_node_value(
+  int handle,
+);
+
 @ffi.Native<ffi.Int64 Function(ffi.Pointer<ffi.Char>)>()
 external int scraper_create_element(
   ffi.Pointer<ffi.Char> tag,
@@ -227,12 +375,88 @@ external void scraper_free(
   int handle,
 );
 
+/// Free every handle and end every session. With the `shared-store` feature, other
+/// isolates share the store, so this only ends the outermost session around the one
+/// the calling thread is in.
 @ffi.Native<ffi.Void Function()>()
 external void scraper_release_all();
 
+/// What became of a handle: 0 if it was never handed out, 1 if it is live, 2 if it
+/// was freed, 3 if it was freed because the document it points into was. Freeing an
+/// invalidated handle with `scraper_free` moves it to 2.
+@ffi.Native<ffi.Int Function(ffi.Int64)>()
+external int scraper_handle_status(
+  int handle,
+);
+
 @ffi.Native<ffi.Void Function()>()
 external void scraper_dispose();
 
+/// Open a handle session, nested in the one the calling thread is in, and enter it:
+/// every handle the thread creates goes into it until the thread enters another
+/// session or this one ends. Returns the session id.
+@ffi.Native<ffi.Int64 Function()>()
+external int scraper_session_begin();
+
+/// Make a session the one the calling thread's new handles go into, or leave every
+/// session for 0. With the `shared-store` feature, an isolate that may have moved to
+/// another thread enters its session again before creating handles. Returns the
+/// session the thread was in before (0 for none), or -1 if no open session has that
+/// id.
+@ffi.Native<ffi.Int64 Function(ffi.Int64)>()
+external int scraper_session_enter(
+  int id,
+);
+
+/// End a session and any still open inside it, freeing every handle they hold as
+/// `scraper_free` would, from whichever thread. Handles created outside any session
+/// are left alone. Returns 0, or -1 if no open session has that id.
+@ffi.Native<ffi.Int Function(ffi.Int64)>()
+external int scraper_session_end(
+  int id,
+);
+
+/// Move a handle out of the innermost session holding it, into the session around
+/// that, or out of every session so it lives until freed. A node promoted without its
+/// document is still freed with the document. Returns 0, or -1 if no session holds
+/// the handle.
+@ffi.Native<ffi.Int Function(ffi.Int64)>()
+external int scraper_session_promote(
+  int handle,
+);
+
+/// Explain why a selector matches what it does under a document or element handle,
+/// as a JSON report: for each comma-separated alternative, each compound with the
+/// candidates it was tried on, how many got past its CSS part, how many each pseudo
+/// filter removed, and how many it matched. `stoppedAt` is the first step of an
+/// alternative that matched nothing. Returns null if the selector is invalid (see
+/// `scraper_selector_error_message`) or the handle is not a document or element.
+@ffi.Native<ffi.Pointer<ffi.Char> Function(ffi.Int64, ffi.Pointer<ffi.Char>)>()
+external ffi.Pointer<ffi.Char> scraper_explain_select(
+  int handle,
+  ffi.Pointer<ffi.Char> selector,
+);
+
+/// Report every live handle as JSON, to find handles that were never freed: `live`
+/// and `counts` (per kind) give how many, `documents` the node count, estimated bytes,
+/// dependent handles and tags of each document, and `handles` the kind, age in
+/// milliseconds and documents of each handle, oldest first. `invalidated` counts
+/// handles freed with their document that were never freed themselves, and `sessions`
+/// the sessions still open. Without the `shared-store` feature the report covers the
+/// calling thread only. Free the result with `scraper_free_string`.
+@ffi.Native<ffi.Pointer<ffi.Char> Function()>()
+external ffi.Pointer<ffi.Char> scraper_handle_report();
+
+/// Attach a tag to a document, such as the source id or URL it came from, shown by
+/// `scraper_handle_report`. A null value removes the tag. Returns 0, or -1 if the
+/// handle is not a live document or the key or value is unreadable.
+@ffi.Native<ffi.Int Function(ffi.Int64, ffi.Pointer<ffi.Char>, ffi.Pointer<ffi.Char>)>()
+external int scraper_set_document_tag(
+  int handle,
+  ffi.Pointer<ffi.Char> key,
+  ffi.Pointer<ffi.Char> value,
+);
+
 @ffi.Native<ffi.Void Function(ffi.Int64, ffi.Pointer<ffi.Char>, ffi.Pointer<ffi.Char>)>()
 external void scraper_set_attr(
   int handle,
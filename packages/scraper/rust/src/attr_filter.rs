@@ -0,0 +1,75 @@
+use crate::java_regex::JavaRegex;
+use crate::selector_error::SelectorError;
+use crate::tokenizer::Part;
+use scraper::ElementRef;
+
+/// Parsed Jsoup-only attribute selector extracted from a compound selector.
+pub(crate) enum AttrFilter {
+    /// `[^prefix]` — any attribute whose (lower-cased) name starts with `prefix`.
+    NamePrefix(String),
+    /// `[attr~=regex]` — attribute present and its value contains a regex match.
+    ValueMatches { key: String, pattern: JavaRegex },
+}
+
+/// Parse the content between `[` and `]`, or `None` if scraper should handle it.
+/// Fails for a Jsoup attribute selector that is malformed.
+fn parse_content<'a>(
+    raw: &'a str,
+    content: &'a str,
+) -> Option<Result<AttrFilter, SelectorError<'a>>> {
+    let trimmed = content.trim();
+    if let Some(prefix) = trimmed.strip_prefix('^') {
+        let prefix = prefix.trim();
+        if prefix.is_empty() {
+            return Some(Err(SelectorError::new(
+                "Attribute prefix must not be empty",
+                raw,
+            )));
+        }
+        return Some(Ok(AttrFilter::NamePrefix(prefix.to_lowercase())));
+    }
+    // `~=` is a regex in Jsoup, not the CSS whitespace-separated word match.
+    let (key, pattern) = content.split_once("~=")?;
+    let key = key.trim();
+    // `[title="a~=b"]`: the `~=` is inside another selector's value.
+    if key.contains(['=', '"', '\'']) {
+        return None;
+    }
+    if key.is_empty() {
+        return Some(Err(SelectorError::new(
+            "Attribute name must not be empty",
+            raw,
+        )));
+    }
+    // Jsoup compiles the remainder verbatim: no trimming or unquoting.
+    Some(
+        JavaRegex::new(pattern)
+            .map(|re| AttrFilter::ValueMatches {
+                key: key.to_lowercase(),
+                pattern: re,
+            })
+            .map_err(|e| SelectorError::new(format!("Pattern syntax error: {e}"), pattern)),
+    )
+}
+
+/// Parse `part` if it is a Jsoup-only attribute selector.
+pub(crate) fn parse_attr<'a>(part: &Part<'a>) -> Option<Result<AttrFilter, SelectorError<'a>>> {
+    match part {
+        Part::Attr { content, raw } => parse_content(raw, content),
+        _ => None,
+    }
+}
+
+/// Check if an element matches an attribute filter.
+pub(crate) fn matches_attr(filter: &AttrFilter, el: &ElementRef) -> bool {
+    let element = el.value();
+    match filter {
+        AttrFilter::NamePrefix(prefix) => element
+            .attrs()
+            .any(|(name, _)| name.to_lowercase().starts_with(prefix.as_str())),
+        AttrFilter::ValueMatches { key, pattern } => element
+            .attrs()
+            .find(|(name, _)| name.eq_ignore_ascii_case(key))
+            .is_some_and(|(_, value)| pattern.is_match(value)),
+    }
+}
@@ -1,11 +1,22 @@
-use ego_tree::NodeRef;
-use regex::Regex;
-use scraper::Node;
+use crate::java_regex::JavaRegex;
+use crate::normalization::Normalization;
+use crate::selector_error::SelectorError;
+use crate::tokenizer::{unescape, unquote, Part};
+use ego_tree::NodeId;
+use scraper::{ElementRef, Node};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
 
 /// Parsed :contains-family or :matches-family pseudo-selector extracted from a CSS selector string.
 pub(crate) struct ContainsFilter {
     pub search_text: String,
     pub kind: ContainsKind,
+    /// Compiled `search_text` for the :matches family.
+    pub pattern: Option<JavaRegex>,
+    /// `search_text` for the :contains family under [`Normalization::Nfkc`] and
+    /// [`Normalization::NfkcKana`], in that order.
+    pub normalized: Option<[String; 2]>,
 }
 
 #[derive(Clone, Copy)]
@@ -24,18 +35,18 @@ pub(crate) enum ContainsKind {
     Matches,
     /// :matchesOwn(regex) — regex match against own text
     MatchesOwn,
+    /// :matchesWholeText(regex) — regex match against raw all text
+    MatchesWholeText,
+    /// :matchesWholeOwnText(regex) — regex match against raw own text
+    MatchesWholeOwnText,
 }
 
 fn normalise_whitespace(s: &str) -> String {
     s.split_whitespace().collect::<Vec<&str>>().join(" ")
 }
 
-fn get_element_text(el: &scraper::ElementRef) -> String {
-    normalise_whitespace(&el.text().collect::<String>())
-}
-
-fn get_own_text(node_ref: &NodeRef<Node>) -> String {
-    let raw: String = node_ref
+fn get_own_text(el: &ElementRef) -> String {
+    let raw: String = el
         .children()
         .filter_map(|c| match c.value() {
             Node::Text(t) => Some(t.text.as_ref()),
@@ -46,13 +57,12 @@ fn get_own_text(node_ref: &NodeRef<Node>) -> String {
     normalise_whitespace(&raw)
 }
 
-fn get_whole_text(el: &scraper::ElementRef) -> String {
+fn get_whole_text(el: &ElementRef) -> String {
     el.text().collect()
 }
 
-fn get_whole_own_text(node_ref: &NodeRef<Node>) -> String {
-    node_ref
-        .children()
+fn get_whole_own_text(el: &ElementRef) -> String {
+    el.children()
         .filter_map(|c| match c.value() {
             Node::Text(t) => Some(t.text.to_string()),
             _ => None,
@@ -61,108 +71,186 @@ fn get_whole_own_text(node_ref: &NodeRef<Node>) -> String {
         .join("")
 }
 
-fn get_data(node_ref: &NodeRef<Node>) -> String {
-    get_whole_own_text(node_ref)
+fn get_data(el: &ElementRef) -> String {
+    get_whole_own_text(el)
 }
 
-// Ordered longest-prefix-first to avoid `:contains(` matching before `:containsOwn(`.
-const CONTAINS_PREFIXES: &[(&str, ContainsKind)] = &[
-    (":containsWholeOwnText(", ContainsKind::WholeOwnText),
-    (":containsWholeText(", ContainsKind::WholeText),
-    (":containsData(", ContainsKind::ContainsData),
-    (":containsOwn(", ContainsKind::ContainsOwn),
-    (":contains(", ContainsKind::Contains),
-    (":matchesOwn(", ContainsKind::MatchesOwn),
-    (":matches(", ContainsKind::Matches),
+const CONTAINS_PSEUDOS: &[(&str, ContainsKind)] = &[
+    ("contains", ContainsKind::Contains),
+    ("containsOwn", ContainsKind::ContainsOwn),
+    ("containsWholeText", ContainsKind::WholeText),
+    ("containsWholeOwnText", ContainsKind::WholeOwnText),
+    ("containsData", ContainsKind::ContainsData),
+    ("matches", ContainsKind::Matches),
+    ("matchesOwn", ContainsKind::MatchesOwn),
+    ("matchesWholeText", ContainsKind::MatchesWholeText),
+    ("matchesWholeOwnText", ContainsKind::MatchesWholeOwnText),
 ];
 
-/// Find the closing `)` that balances nested parentheses.
-fn find_closing_paren(s: &str) -> Option<usize> {
-    let mut depth = 0;
-    for (i, c) in s.char_indices() {
-        match c {
-            '(' => depth += 1,
-            ')' if depth > 0 => depth -= 1,
-            ')' => return Some(i),
-            _ => {}
+/// Build the filter for one pseudo argument. Quotes around the argument are dropped;
+/// text arguments are also unescaped, while regexes keep their backslashes.
+fn build_filter(kind: ContainsKind, arg: &str) -> Result<ContainsFilter, SelectorError<'_>> {
+    let arg = unquote(arg);
+    // The needle as written, before the kind's own case handling.
+    let text = match kind {
+        ContainsKind::Contains | ContainsKind::ContainsOwn => normalise_whitespace(&unescape(arg)),
+        ContainsKind::ContainsData | ContainsKind::WholeText | ContainsKind::WholeOwnText => {
+            unescape(arg)
         }
-    }
-    None
+        ContainsKind::Matches
+        | ContainsKind::MatchesOwn
+        | ContainsKind::MatchesWholeText
+        | ContainsKind::MatchesWholeOwnText => {
+            let pattern = JavaRegex::new(arg)
+                .map_err(|e| SelectorError::new(format!("Pattern syntax error: {e}"), arg))?;
+            return Ok(ContainsFilter {
+                search_text: arg.to_string(),
+                kind,
+                pattern: Some(pattern),
+                normalized: None,
+            });
+        }
+    };
+    let lower = kind.ignores_case();
+    let normalized = Some([
+        Normalization::Nfkc.apply(&text, lower),
+        Normalization::NfkcKana.apply(&text, lower),
+    ]);
+    let search_text = if lower { text.to_lowercase() } else { text };
+    Ok(ContainsFilter {
+        search_text,
+        kind,
+        pattern: None,
+        normalized,
+    })
+}
+
+/// Parse `part` if it is a :contains-family or :matches-family pseudo-selector.
+/// Fails if a :matches-family pattern does not compile.
+pub(crate) fn parse_contains<'a>(
+    part: &Part<'a>,
+) -> Option<Result<ContainsFilter, SelectorError<'a>>> {
+    CONTAINS_PSEUDOS
+        .iter()
+        .find_map(|&(name, kind)| part.pseudo_arg(name).map(|arg| build_filter(kind, arg)))
+}
+
+/// Which text of an element a filter searches.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum TextKind {
+    Text,
+    TextLower,
+    OwnText,
+    OwnTextLower,
+    WholeText,
+    WholeOwnText,
+    Data,
+    DataLower,
 }
 
-/// Strip all :contains-family and :matches-family pseudo-selectors from a CSS selector string.
-/// Returns the base selector and a list of filters (all must match).
-pub(crate) fn strip_contains(sel_str: &str) -> (String, Vec<ContainsFilter>) {
-    let mut remaining = sel_str.to_string();
-    let mut filters = Vec::new();
-
-    'outer: loop {
-        for &(prefix, kind) in CONTAINS_PREFIXES {
-            if let Some(start) = remaining.find(prefix) {
-                let text_start = start + prefix.len();
-                if let Some(rel_end) = find_closing_paren(&remaining[text_start..]) {
-                    let raw_text = &remaining[text_start..text_start + rel_end];
-                    let base = format!(
-                        "{}{}",
-                        &remaining[..start],
-                        &remaining[text_start + rel_end + 1..]
-                    );
-                    let search_text = match kind {
-                        ContainsKind::Contains | ContainsKind::ContainsOwn => {
-                            normalise_whitespace(raw_text).to_lowercase()
-                        }
-                        ContainsKind::ContainsData => raw_text.to_lowercase(),
-                        ContainsKind::WholeText | ContainsKind::WholeOwnText => {
-                            raw_text.to_string()
-                        }
-                        ContainsKind::Matches | ContainsKind::MatchesOwn => {
-                            raw_text.to_string()
-                        }
-                    };
-                    filters.push(ContainsFilter {
-                        search_text,
-                        kind,
-                    });
-                    remaining = base;
-                    continue 'outer;
-                }
+impl ContainsKind {
+    fn text_kind(self) -> TextKind {
+        match self {
+            ContainsKind::Contains => TextKind::TextLower,
+            ContainsKind::ContainsOwn => TextKind::OwnTextLower,
+            ContainsKind::WholeText | ContainsKind::MatchesWholeText => TextKind::WholeText,
+            ContainsKind::WholeOwnText | ContainsKind::MatchesWholeOwnText => {
+                TextKind::WholeOwnText
             }
+            ContainsKind::ContainsData => TextKind::DataLower,
+            ContainsKind::Matches => TextKind::Text,
+            ContainsKind::MatchesOwn => TextKind::OwnText,
+        }
+    }
+
+    /// Whether the filter compares text without case, as all but the whole-text
+    /// :contains filters do.
+    fn ignores_case(self) -> bool {
+        !matches!(self, ContainsKind::WholeText | ContainsKind::WholeOwnText)
+    }
+
+    /// The text a :contains-family filter normalizes, in place of its usual one.
+    fn raw_text_kind(self) -> TextKind {
+        match self {
+            ContainsKind::Contains => TextKind::Text,
+            ContainsKind::ContainsOwn => TextKind::OwnText,
+            ContainsKind::ContainsData => TextKind::Data,
+            _ => self.text_kind(),
         }
-        break;
     }
 
-    (remaining, filters)
+    /// Whether the filter reads the whole subtree rather than the element's own children.
+    pub(crate) fn reads_subtree(self) -> bool {
+        matches!(
+            self.text_kind(),
+            TextKind::Text | TextKind::TextLower | TextKind::WholeText
+        )
+    }
+}
+
+/// Element texts computed during one select, so each is collected once per element
+/// instead of once per filter and candidate.
+#[derive(Default)]
+pub(crate) struct TextCache {
+    texts: RefCell<HashMap<(NodeId, TextKind, Normalization), Rc<str>>>,
 }
 
-/// Check if an element matches a contains filter.
+impl TextCache {
+    /// The element's text, whitespace-normalized as Jsoup's `Element.text()`.
+    pub(crate) fn text(&self, el: &ElementRef) -> Rc<str> {
+        self.get(el, TextKind::Text)
+    }
+
+    fn get(&self, el: &ElementRef, kind: TextKind) -> Rc<str> {
+        self.get_normalized(el, kind, Normalization::None)
+    }
+
+    fn get_normalized(&self, el: &ElementRef, kind: TextKind, mode: Normalization) -> Rc<str> {
+        let key = (el.id(), kind, mode);
+        if let Some(text) = self.texts.borrow().get(&key) {
+            return text.clone();
+        }
+        let text: Rc<str> = match kind {
+            _ if mode != Normalization::None => mode
+                .apply(
+                    &self.get(el, kind),
+                    kind != TextKind::WholeText && kind != TextKind::WholeOwnText,
+                )
+                .into(),
+            TextKind::Text => normalise_whitespace(&self.get(el, TextKind::WholeText)).into(),
+            TextKind::TextLower => self.get(el, TextKind::Text).to_lowercase().into(),
+            TextKind::OwnText => get_own_text(el).into(),
+            TextKind::OwnTextLower => self.get(el, TextKind::OwnText).to_lowercase().into(),
+            TextKind::WholeText => get_whole_text(el).into(),
+            TextKind::WholeOwnText => get_whole_own_text(el).into(),
+            TextKind::Data => get_data(el).into(),
+            TextKind::DataLower => self.get(el, TextKind::Data).to_lowercase().into(),
+        };
+        self.texts.borrow_mut().insert(key, text.clone());
+        text
+    }
+}
+
+/// Check if an element matches a contains filter. `mode` applies to the :contains
+/// family only; :matches patterns see the text as it is.
 pub(crate) fn matches_filter(
     filter: &ContainsFilter,
-    el: &scraper::ElementRef,
-    node_ref: &NodeRef<Node>,
+    el: &ElementRef,
+    texts: &TextCache,
+    mode: Normalization,
 ) -> bool {
-    match filter.kind {
-        ContainsKind::Contains => get_element_text(el)
-            .to_lowercase()
-            .contains(&filter.search_text),
-        ContainsKind::ContainsOwn => get_own_text(node_ref)
-            .to_lowercase()
-            .contains(&filter.search_text),
-        ContainsKind::WholeText => get_whole_text(el).contains(&filter.search_text),
-        ContainsKind::WholeOwnText => get_whole_own_text(node_ref).contains(&filter.search_text),
-        ContainsKind::ContainsData => get_data(node_ref)
-            .to_lowercase()
-            .contains(&filter.search_text),
-        ContainsKind::Matches => {
-            match Regex::new(&filter.search_text) {
-                Ok(re) => re.is_match(&get_element_text(el)),
-                Err(_) => false,
-            }
+    let needle = match (&filter.normalized, mode) {
+        (Some([nfkc, _]), Normalization::Nfkc) => nfkc,
+        (Some([_, kana]), Normalization::NfkcKana) => kana,
+        _ => {
+            let haystack = texts.get(el, filter.kind.text_kind());
+            return match &filter.pattern {
+                Some(re) => re.is_match(&haystack),
+                None => haystack.contains(filter.search_text.as_str()),
+            };
         }
-        ContainsKind::MatchesOwn => {
-            match Regex::new(&filter.search_text) {
-                Ok(re) => re.is_match(&get_own_text(node_ref)),
-                Err(_) => false,
-            }
-        }
-    }
+    };
+    texts
+        .get_normalized(el, filter.kind.raw_text_kind(), mode)
+        .contains(needle.as_str())
 }
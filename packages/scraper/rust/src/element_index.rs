@@ -0,0 +1,155 @@
+use ego_tree::iter::Edge;
+use ego_tree::NodeId;
+use scraper::selector::Parser;
+use scraper::{Html, Node};
+use selectors::parser::{Combinator, Component, ParseRelative, SelectorList};
+use std::collections::HashMap;
+
+/// Every node's preorder position in the document's tree.
+pub(crate) fn document_order(html: &Html) -> HashMap<NodeId, usize> {
+    html.tree
+        .root()
+        .descendants()
+        .enumerate()
+        .map(|(i, n)| (n.id(), i))
+        .collect()
+}
+
+/// Sort nodes into document order, for merging results that are each in order
+/// already. Nodes detached from the tree keep their place at the end.
+pub(crate) fn sort_in_document_order(html: &Html, ids: &mut [NodeId]) {
+    let order = document_order(html);
+    ids.sort_by_key(|id| order.get(id).copied().unwrap_or(usize::MAX));
+}
+
+/// Something the leftmost compound of a selector requires of an element, which an
+/// [`ElementIndex`] can look up.
+#[derive(Clone)]
+pub(crate) enum IndexKey {
+    Id(String),
+    Class(String),
+    /// Lower-cased, as HTML tag names match case-insensitively.
+    Tag(String),
+}
+
+/// A document's elements by id, class and tag name, each list in document order.
+/// Built on first use and dropped whenever the tree changes.
+pub(crate) struct ElementIndex {
+    ids: HashMap<String, Vec<NodeId>>,
+    classes: HashMap<String, Vec<NodeId>>,
+    tags: HashMap<String, Vec<NodeId>>,
+    /// Each element's preorder position and the position just past its subtree.
+    spans: HashMap<NodeId, (usize, usize)>,
+}
+
+impl ElementIndex {
+    pub(crate) fn build(html: &Html) -> Self {
+        let mut index = ElementIndex {
+            ids: HashMap::new(),
+            classes: HashMap::new(),
+            tags: HashMap::new(),
+            spans: HashMap::new(),
+        };
+        let mut position = 0;
+        for edge in html.tree.root().traverse() {
+            match edge {
+                Edge::Open(node) => {
+                    position += 1;
+                    let Node::Element(el) = node.value() else {
+                        continue;
+                    };
+                    let id = node.id();
+                    index.spans.insert(id, (position, position));
+                    index
+                        .tags
+                        .entry(str::to_ascii_lowercase(&el.name.local))
+                        .or_default()
+                        .push(id);
+                    if let Some(value) = el.id() {
+                        index.ids.entry(value.to_string()).or_default().push(id);
+                    }
+                    for class in el.classes() {
+                        let list = index.classes.entry(class.to_string()).or_default();
+                        // `class="a a"` lists the element once.
+                        if list.last() != Some(&id) {
+                            list.push(id);
+                        }
+                    }
+                }
+                Edge::Close(node) => {
+                    if let Some(span) = index.spans.get_mut(&node.id()) {
+                        span.1 = position + 1;
+                    }
+                }
+            }
+        }
+        index
+    }
+
+    fn lookup(&self, key: &IndexKey) -> &[NodeId] {
+        let (map, name) = match key {
+            IndexKey::Id(name) => (&self.ids, name),
+            IndexKey::Class(name) => (&self.classes, name),
+            IndexKey::Tag(name) => (&self.tags, name),
+        };
+        map.get(name).map_or(&[], Vec::as_slice)
+    }
+
+    /// The elements whose subtrees hold every match of a selector, given the keys of
+    /// each of its alternatives: the outermost elements having, for some alternative,
+    /// the key of it with the fewest elements. In document order.
+    pub(crate) fn anchors(&self, alternatives: &[Vec<IndexKey>]) -> Vec<NodeId> {
+        let mut found: Vec<(usize, usize, NodeId)> = Vec::new();
+        for keys in alternatives {
+            let Some(ids) = keys
+                .iter()
+                .map(|key| self.lookup(key))
+                .min_by_key(|ids| ids.len())
+            else {
+                continue;
+            };
+            found.extend(ids.iter().map(|id| {
+                let (start, end) = self.spans[id];
+                (start, end, *id)
+            }));
+        }
+        found.sort_unstable_by_key(|&(start, _, _)| start);
+        let mut anchors = Vec::new();
+        let mut covered = 0;
+        for (start, end, id) in found {
+            if start >= covered {
+                anchors.push(id);
+                covered = end;
+            }
+        }
+        anchors
+    }
+}
+
+/// The index keys of the leftmost compound of each alternative of a CSS selector,
+/// when every match lies within an element matching that compound. `None` if some
+/// alternative has no key, or leaves the compound's subtree through `+` or `~`.
+pub(crate) fn leftmost_keys(css: &str) -> Option<Vec<Vec<IndexKey>>> {
+    let mut input = cssparser::ParserInput::new(css);
+    let mut parser = cssparser::Parser::new(&mut input);
+    let list = SelectorList::parse(&Parser, &mut parser, ParseRelative::No).ok()?;
+    list.slice()
+        .iter()
+        .map(|selector| {
+            let mut keys = Vec::new();
+            for component in selector.iter_raw_parse_order_from(0) {
+                match component {
+                    Component::ID(name) => keys.push(IndexKey::Id(name.0.to_string())),
+                    Component::Class(name) => keys.push(IndexKey::Class(name.0.to_string())),
+                    Component::LocalName(name) => {
+                        keys.push(IndexKey::Tag(str::to_ascii_lowercase(&name.lower_name.0)))
+                    }
+                    Component::Combinator(Combinator::Descendant | Combinator::Child) => break,
+                    Component::Combinator(_) => return None,
+                    _ => {}
+                }
+            }
+            (!keys.is_empty()).then_some(keys)
+        })
+        .collect()
+}
@@ -0,0 +1,197 @@
+use crate::element_index::sort_in_document_order;
+use crate::handle_store::{get_node, is_document};
+use crate::jsoup_selector::JsoupSelector;
+use crate::pseudo_element::PseudoElement;
+use crate::{cstr_to_str, project, selector_error, to_cstring, with_prepared};
+use ego_tree::NodeId;
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use std::ffi::c_char;
+use std::ptr;
+
+/// What one compound of a selector matched while it was explained.
+pub(crate) struct StepReport {
+    /// The compound as written.
+    pub compound: String,
+    /// How it relates to the previous step; `None` for the first.
+    pub combinator: Option<&'static str>,
+    /// Elements the combinator reached from the previous step's matches (every element
+    /// for the first step).
+    pub candidates: usize,
+    /// Candidates left after the part of the compound scraper evaluates.
+    pub matched_css: usize,
+    /// Each pseudo filter as written, with how many candidates it removed, in the
+    /// order they ran.
+    pub filters: Vec<(String, usize)>,
+    pub matches: usize,
+}
+
+/// The steps of one comma-separated alternative of a selector.
+pub(crate) struct ChainReport {
+    pub selector: String,
+    pub steps: Vec<StepReport>,
+    /// What the alternative matched under the select's context.
+    pub matches: Vec<NodeId>,
+}
+
+fn step_json(step: &StepReport) -> Value {
+    json!({
+        "compound": step.compound,
+        "combinator": step.combinator,
+        "candidates": step.candidates,
+        "matchedCss": step.matched_css,
+        "filters": step
+            .filters
+            .iter()
+            .map(|(filter, removed)| json!({ "filter": filter, "removed": removed }))
+            .collect::<Vec<_>>(),
+        "matches": step.matches,
+    })
+}
+
+fn chain_json(chain: &ChainReport) -> Value {
+    json!({
+        "selector": chain.selector,
+        "matches": chain.matches.len(),
+        // The first step nothing got past, where the alternative stopped matching.
+        "stoppedAt": chain.steps.iter().position(|step| step.matches == 0),
+        "steps": chain.steps.iter().map(step_json).collect::<Vec<_>>(),
+    })
+}
+
+/// Explain `sel_str` under a document or element handle. `None` if the selector is
+/// invalid (recorded for `scraper_selector_error_*`) or the handle is neither.
+fn explain(handle: i64, sel_str: &str) -> Option<Value> {
+    let sel = selector_error::record(sel_str, JsoupSelector::parse_compounds(sel_str))?;
+    let (doc_handle, root) = if is_document(handle) {
+        (handle, None)
+    } else {
+        let entry = get_node(handle).filter(|e| !e.is_text && e.attr.is_none())?;
+        (entry.doc_handle, Some(entry.node_id))
+    };
+    with_prepared(doc_handle, &sel, &[root], |doc| {
+        let chains = sel.explain(doc, root);
+        let mut seen = HashSet::new();
+        let mut matches: Vec<NodeId> = chains
+            .iter()
+            .flat_map(|chain| &chain.matches)
+            .copied()
+            .filter(|id| seen.insert(*id))
+            .collect();
+        if chains.len() > 1 {
+            sort_in_document_order(&doc.html, &mut matches);
+        }
+        let mut report = json!({
+            "selector": sel_str,
+            "matches": matches.len(),
+            "alternatives": chains.iter().map(chain_json).collect::<Vec<_>>(),
+        });
+        if let Some(projection) = sel.projection() {
+            let pseudo = match &projection.pseudo {
+                PseudoElement::Text => "::text".to_string(),
+                PseudoElement::Attr(name) => format!("::attr({name})"),
+            };
+            let results = project(doc, doc_handle, matches, &sel, &mut HashSet::new()).len();
+            report["pseudoElement"] = json!(pseudo);
+            report["results"] = json!(results);
+        }
+        report
+    })
+}
+
+/// Explain why a selector matches what it does under a document or element handle,
+/// as a JSON report: for each comma-separated alternative, each compound with the
+/// candidates it was tried on, how many got past its CSS part, how many each pseudo
+/// filter removed, and how many it matched. `stoppedAt` is the first step of an
+/// alternative that matched nothing. Returns null if the selector is invalid (see
+/// `scraper_selector_error_message`) or the handle is not a document or element.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn scraper_explain_select(
+    handle: i64,
+    selector: *const c_char,
+) -> *mut c_char {
+    let sel_str = match unsafe { cstr_to_str(selector) } {
+        Some(s) => s,
+        None => {
+            selector_error::record_unreadable();
+            return ptr::null_mut();
+        }
+    };
+    explain(handle, sel_str).map_or(ptr::null_mut(), |report| to_cstring(&report.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{parse, selector, take_string};
+    use crate::{scraper_free, select_first};
+
+    const HTML: &str = r#"<div id="d1"><p class="a">one</p><p class="a b">two</p><span class="a">three</span></div><div id="d2"><p>four</p></div>"#;
+
+    fn explained(handle: i64, query: &str) -> Value {
+        let query = std::ffi::CString::new(query).unwrap();
+        let json = take_string(unsafe { scraper_explain_select(handle, query.as_ptr()) });
+        serde_json::from_str(&json.unwrap()).unwrap()
+    }
+
+    #[test]
+    fn reports_each_step() {
+        let doc = parse(HTML);
+        let report = explained(doc, "div > p.a:contains(two)");
+        assert_eq!(report["selector"], "div > p.a:contains(two)");
+        assert_eq!(report["matches"], 1);
+        let alternatives = report["alternatives"].as_array().unwrap();
+        assert_eq!(alternatives.len(), 1);
+        let steps = alternatives[0]["steps"].as_array().unwrap();
+        assert_eq!(steps.len(), 2);
+        assert_eq!(steps[0]["compound"], "div");
+        assert_eq!(steps[0]["combinator"], Value::Null);
+        assert_eq!(steps[0]["matches"], 2);
+        assert_eq!(steps[1]["combinator"], ">");
+        assert_eq!(steps[1]["candidates"], 4);
+        assert_eq!(steps[1]["matchedCss"], 2);
+        assert_eq!(
+            steps[1]["filters"],
+            json!([{ "filter": ":contains(two)", "removed": 1 }])
+        );
+        assert_eq!(steps[1]["matches"], 1);
+        assert_eq!(alternatives[0]["stoppedAt"], Value::Null);
+        unsafe { scraper_free(doc) };
+    }
+
+    #[test]
+    fn reports_where_an_alternative_stopped() {
+        let doc = parse(HTML);
+        let report = explained(doc, "section p, span.a");
+        assert_eq!(report["matches"], 1);
+        let alternatives = report["alternatives"].as_array().unwrap();
+        assert_eq!(alternatives[0]["selector"], "section p");
+        assert_eq!(alternatives[0]["matches"], 0);
+        assert_eq!(alternatives[0]["stoppedAt"], 0);
+        assert_eq!(alternatives[1]["matches"], 1);
+        assert_eq!(alternatives[1]["stoppedAt"], Value::Null);
+        unsafe { scraper_free(doc) };
+    }
+
+    #[test]
+    fn reports_projections_and_context() {
+        let doc = parse(HTML);
+        let report = explained(doc, ".a::text");
+        assert_eq!(report["matches"], 3);
+        assert_eq!(report["pseudoElement"], "::text");
+        assert_eq!(report["results"], 3);
+
+        let d2 = select_first(doc, &selector("#d2"));
+        let report = explained(d2, "p");
+        assert_eq!(report["matches"], 1);
+        unsafe { scraper_free(doc) };
+    }
+
+    #[test]
+    fn invalid_selectors_give_null() {
+        let doc = parse(HTML);
+        let json = unsafe { scraper_explain_select(doc, c"p:foo".as_ptr()) };
+        assert!(json.is_null());
+        unsafe { scraper_free(doc) };
+    }
+}
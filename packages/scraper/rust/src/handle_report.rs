@@ -0,0 +1,262 @@
+use crate::handle_store::{
+    invalidated_count, live_handles, open_sessions, peek_doc, with_doc_mut, DocEntry, HandleKind,
+};
+use crate::{cstr_to_str, to_cstring};
+use markup5ever::QualName;
+use scraper::{Node, StrTendril};
+use serde_json::{json, Map, Value};
+use std::ffi::{c_char, c_int};
+use std::mem::size_of;
+
+/// What a tree node costs before its text and attributes: the value and the ids
+/// linking it to its parent, siblings and first and last children.
+const NODE_BYTES: usize = size_of::<Node>() + 5 * size_of::<ego_tree::NodeId>();
+
+/// A rough count of the bytes a document holds. Nodes removed from the tree still
+/// count, as they stay allocated until the document is freed.
+fn estimated_bytes(doc: &DocEntry) -> usize {
+    let nodes: usize = doc
+        .html
+        .tree
+        .values()
+        .map(|node| {
+            NODE_BYTES
+                + match node {
+                    Node::Text(text) => text.len(),
+                    Node::Comment(comment) => comment.len(),
+                    Node::Element(el) => el
+                        .attrs
+                        .iter()
+                        .map(|(_, value)| size_of::<(QualName, StrTendril)>() + value.len())
+                        .sum(),
+                    _ => 0,
+                }
+        })
+        .sum();
+    nodes + doc.base_uri.len()
+}
+
+fn report() -> Value {
+    let live = live_handles();
+    let mut counts = Map::new();
+    for kind in [
+        HandleKind::Document,
+        HandleKind::Node,
+        HandleKind::NodeList,
+        HandleKind::Selector,
+        HandleKind::Cursor,
+    ] {
+        let count = live.iter().filter(|handle| handle.kind == kind).count();
+        counts.insert(kind.key().to_string(), json!(count));
+    }
+    let documents: Vec<Value> = live
+        .iter()
+        .filter(|handle| handle.kind == HandleKind::Document)
+        .filter_map(|handle| {
+            let dependents = live
+                .iter()
+                .filter(|other| other.documents.contains(&handle.handle))
+                .count();
+            peek_doc(handle.handle, |doc| {
+                json!({
+                    "handle": handle.handle,
+                    "ageMs": handle.created.elapsed().as_millis() as u64,
+                    "nodes": doc.html.tree.values().count(),
+                    "estimatedBytes": estimated_bytes(doc),
+                    "dependents": dependents,
+                    "tags": doc.tags,
+                })
+            })
+        })
+        .collect();
+    let handles: Vec<Value> = live
+        .iter()
+        .map(|handle| {
+            let mut entry = json!({
+                "handle": handle.handle,
+                "kind": handle.kind.key(),
+                "ageMs": handle.created.elapsed().as_millis() as u64,
+            });
+            if !handle.documents.is_empty() {
+                entry["documents"] = json!(handle.documents);
+            }
+            entry
+        })
+        .collect();
+    json!({
+        "live": live.len(),
+        "counts": counts,
+        "invalidated": invalidated_count(),
+        "sessions": open_sessions(),
+        "documents": documents,
+        "handles": handles,
+    })
+}
+
+/// Report every live handle as JSON, to find handles that were never freed: `live`
+/// and `counts` (per kind) give how many, `documents` the node count, estimated bytes,
+/// dependent handles and tags of each document, and `handles` the kind, age in
+/// milliseconds and documents of each handle, oldest first. `invalidated` counts
+/// handles freed with their document that were never freed themselves, and `sessions`
+/// the sessions still open. Without the `shared-store` feature the report covers the
+/// calling thread only. Free the result with `scraper_free_string`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn scraper_handle_report() -> *mut c_char {
+    to_cstring(&report().to_string())
+}
+
+/// Attach a tag to a document, such as the source id or URL it came from, shown by
+/// `scraper_handle_report`. A null value removes the tag. Returns 0, or -1 if the
+/// handle is not a live document or the key or value is unreadable.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn scraper_set_document_tag(
+    handle: i64,
+    key: *const c_char,
+    value: *const c_char,
+) -> c_int {
+    let Some(key) = (unsafe { cstr_to_str(key) }) else {
+        return -1;
+    };
+    let value = if value.is_null() {
+        None
+    } else {
+        match unsafe { cstr_to_str(value) } {
+            Some(value) => Some(value),
+            None => return -1,
+        }
+    };
+    with_doc_mut(handle, |doc| match value {
+        Some(value) => {
+            doc.tags.insert(key.to_string(), value.to_string());
+        }
+        None => {
+            doc.tags.remove(key);
+        }
+    })
+    .map_or(-1, |()| 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::parse;
+    use crate::{scraper_compile_selector, scraper_free};
+    use crate::{scraper_select, scraper_select_cursor, scraper_select_first};
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    /// The entry `report[field]` has for `handle`.
+    fn entry(report: &Value, field: &str, handle: i64) -> Value {
+        report[field]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|entry| entry["handle"] == handle)
+            .cloned()
+            .unwrap_or(Value::Null)
+    }
+
+    #[test]
+    fn reports_live_handles_by_kind() {
+        let doc = parse("<p>x</p><p>y</p>");
+        let p = unsafe { scraper_select_first(doc, c"p".as_ptr()) };
+        let list = unsafe { scraper_select(doc, c"p".as_ptr()) };
+        let sel = unsafe { scraper_compile_selector(c"p".as_ptr()) };
+        let cursor = unsafe { scraper_select_cursor(doc, c"p".as_ptr(), 0, -1) };
+        let report = report();
+
+        // With `shared-store` other tests' handles are in the report too.
+        #[cfg(not(feature = "shared-store"))]
+        {
+            assert_eq!(report["live"], 5);
+            assert_eq!(
+                report["counts"],
+                json!({ "document": 1, "node": 1, "nodeList": 1, "selector": 1, "cursor": 1 })
+            );
+        }
+        for (handle, kind) in [
+            (doc, "document"),
+            (p, "node"),
+            (list, "nodeList"),
+            (sel, "selector"),
+            (cursor, "cursor"),
+        ] {
+            assert_eq!(entry(&report, "handles", handle)["kind"], kind);
+        }
+        assert_eq!(entry(&report, "handles", p)["documents"], json!([doc]));
+        assert_eq!(entry(&report, "handles", sel)["documents"], Value::Null);
+
+        let document = entry(&report, "documents", doc);
+        assert_eq!(document["dependents"], 3);
+        assert!(document["nodes"].as_u64().unwrap() > 5);
+        assert!(document["estimatedBytes"].as_u64().unwrap() > 0);
+
+        for handle in [p, list, sel, cursor, doc] {
+            unsafe { scraper_free(handle) };
+        }
+        let report = super::report();
+        for handle in [doc, p, list, sel, cursor] {
+            assert_eq!(entry(&report, "handles", handle), Value::Null);
+        }
+    }
+
+    #[test]
+    fn reports_document_tags() {
+        let doc = parse("<p>x</p>");
+        let set = |key: &std::ffi::CStr, value: *const c_char| unsafe {
+            scraper_set_document_tag(doc, key.as_ptr(), value)
+        };
+        assert_eq!(set(c"source", c"feed-1".as_ptr()), 0);
+        assert_eq!(set(c"url", c"https://example.com/".as_ptr()), 0);
+        assert_eq!(
+            entry(&report(), "documents", doc)["tags"],
+            json!({ "source": "feed-1", "url": "https://example.com/" })
+        );
+        assert_eq!(set(c"url", std::ptr::null()), 0);
+        assert_eq!(
+            entry(&report(), "documents", doc)["tags"],
+            json!({ "source": "feed-1" })
+        );
+
+        let p = unsafe { scraper_select_first(doc, c"p".as_ptr()) };
+        assert_eq!(
+            unsafe { scraper_set_document_tag(p, c"a".as_ptr(), c"b".as_ptr()) },
+            -1
+        );
+        unsafe { scraper_free(doc) };
+        assert_eq!(set(c"source", c"feed-2".as_ptr()), -1);
+        unsafe { scraper_free(p) };
+    }
+
+    #[test]
+    fn reports_ages_oldest_first() {
+        let old = parse("<p>x</p>");
+        sleep(Duration::from_millis(20));
+        let new = parse("<p>y</p>");
+        let report = report();
+        let age = |handle| entry(&report, "handles", handle)["ageMs"].as_u64().unwrap();
+        assert!(age(old) >= 20);
+        assert!(age(old) > age(new));
+        assert!(entry(&report, "documents", old)["ageMs"].as_u64().unwrap() >= 20);
+        let handles = report["handles"].as_array().unwrap();
+        let position = |handle| handles.iter().position(|entry| entry["handle"] == handle);
+        assert!(position(old) < position(new));
+        unsafe { scraper_free(old) };
+        unsafe { scraper_free(new) };
+    }
+
+    #[cfg(not(feature = "shared-store"))]
+    #[test]
+    fn reports_open_sessions() {
+        use crate::{scraper_session_begin, scraper_session_end};
+
+        assert_eq!(report()["sessions"], 0);
+        let session = unsafe { scraper_session_begin() };
+        let doc = parse("<p>x</p>");
+        assert_eq!(report()["sessions"], 1);
+        unsafe { scraper_session_end(session) };
+        let report = report();
+        assert_eq!(report["sessions"], 0);
+        assert_eq!(entry(&report, "handles", doc), Value::Null);
+    }
+}
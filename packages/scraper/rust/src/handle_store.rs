@@ -1,83 +1,578 @@
+use crate::element_index::ElementIndex;
+use crate::jsoup_selector::{JsoupSelector, Resume};
+use crate::normalization::Normalization;
 use ego_tree::NodeId;
+use markup5ever::QualName;
 use scraper::Html;
-use std::cell::RefCell;
-use std::collections::HashMap;
-use std::sync::atomic::{AtomicI64, Ordering};
+use std::cell::{Cell, OnceCell, RefCell};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicI64, AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+use sync::{lock, read, Global, Lock, Shared, SHARD_BITS};
 
-/// Global atomic counter for unique handle generation.
-static NEXT_HANDLE: AtomicI64 = AtomicI64::new(1);
+/// Handles live in per-thread stores: a handle means nothing on another thread.
+#[cfg(not(feature = "shared-store"))]
+mod sync {
+    pub(super) use std::cell::RefCell as Lock;
+    pub(super) use std::rc::Rc as Shared;
+    pub(super) use std::thread::LocalKey as Global;
 
-fn next_handle() -> i64 {
-    NEXT_HANDLE.fetch_add(1, Ordering::Relaxed)
+    pub(super) const SHARD_BITS: u32 = 0;
+
+    pub(super) fn lock<T>(lock: &Lock<T>) -> std::cell::RefMut<'_, T> {
+        lock.borrow_mut()
+    }
+
+    pub(super) fn read<T>(lock: &Lock<T>) -> std::cell::Ref<'_, T> {
+        lock.borrow()
+    }
+
+    macro_rules! stores {
+        ($($(#[$meta:meta])* static $name:ident: $ty:ty = $init:expr;)*) => {
+            thread_local! {
+                $($(#[$meta])* static $name: $ty = const { $init };)*
+            }
+        };
+    }
+    pub(super) use stores;
+}
+
+/// Handles live in one process-wide store, so a document parsed on one Dart isolate
+/// can be queried from another. Each kind is split into shards with a lock each, and
+/// each document and cursor has its own lock. Sessions are shared too, so one opened
+/// on one thread can be entered or ended from another.
+#[cfg(feature = "shared-store")]
+mod sync {
+    pub(super) use std::sync::Arc as Shared;
+    pub(super) use std::sync::Mutex as Lock;
+    use std::sync::{MutexGuard, PoisonError};
+
+    pub(super) const SHARD_BITS: u32 = 4;
+
+    /// A process-wide static, read through `with` like a thread-local.
+    pub(super) struct Global<T>(pub(super) T);
+
+    impl<T> Global<T> {
+        pub(super) fn with<R>(&'static self, f: impl FnOnce(&T) -> R) -> R {
+            f(&self.0)
+        }
+    }
+
+    // A panic while a lock is held leaves the value as consistent as a `RefCell`
+    // would, so poisoning is ignored.
+    pub(super) fn lock<T>(lock: &Lock<T>) -> MutexGuard<'_, T> {
+        lock.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub(super) fn read<T>(lock: &Lock<T>) -> MutexGuard<'_, T> {
+        self::lock(lock)
+    }
+
+    macro_rules! stores {
+        ($($(#[$meta:meta])* static $name:ident: $ty:ty = $init:expr;)*) => {
+            $($(#[$meta])* static $name: Global<$ty> = Global($init);)*
+        };
+    }
+    pub(super) use stores;
 }
 
 /// A parsed document with its base URI.
 pub struct DocEntry {
     pub html: Html,
     pub base_uri: String,
+    /// Synthetic text-wrapping elements `:matchText` has put in the tree for the
+    /// select running now.
+    pub pseudo_text: HashSet<NodeId>,
+    /// How selects compare text in the :contains family, unless the selector says.
+    pub normalization: Normalization,
+    /// Caller-supplied labels, such as the source id or URL, shown in handle reports.
+    pub tags: BTreeMap<String, String>,
+    index: OnceCell<ElementIndex>,
+}
+
+impl DocEntry {
+    /// The document's element indexes, built on first use.
+    pub(crate) fn index(&self) -> &ElementIndex {
+        self.index.get_or_init(|| ElementIndex::build(&self.html))
+    }
+
+    /// Drop the element indexes after a change to the tree.
+    pub(crate) fn tree_changed(&mut self) {
+        self.index.take();
+    }
 }
 
 /// A node reference: NodeId + owning document handle.
-#[derive(Clone, Copy)]
+#[derive(Clone)]
 pub struct NodeEntry {
     pub node_id: NodeId,
     pub doc_handle: i64,
     pub is_text: bool,
+    /// Set for an attribute of the element at `node_id`, as selected by XPath.
+    pub attr: Option<QualName>,
+}
+
+/// An open selection, advanced one result at a time.
+pub(crate) struct Cursor {
+    pub selector: Arc<JsoupSelector>,
+    pub doc_handle: i64,
+    /// The element the select runs under, or `None` for the whole document.
+    pub root: Option<NodeId>,
+    /// Where the search resumes, once anything matched.
+    pub last: Option<Resume>,
+    /// Results of the last match not handed out yet; a pseudo-element can give several.
+    pub pending: VecDeque<NodeEntry>,
+    /// Elements a `p ::text`-style projection already took from.
+    pub seen: HashSet<NodeId>,
+    /// Results still to skip before the first one is returned.
+    pub offset: usize,
+    /// Results left to return, if limited.
+    pub remaining: Option<usize>,
+    pub exhausted: bool,
+}
+
+/// What a handle names, kept in its top bits.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum HandleKind {
+    Document = 1,
+    Node = 2,
+    NodeList = 3,
+    Selector = 4,
+    Cursor = 5,
+}
+
+impl HandleKind {
+    fn from_bits(bits: i64) -> Option<Self> {
+        match bits {
+            1 => Some(HandleKind::Document),
+            2 => Some(HandleKind::Node),
+            3 => Some(HandleKind::NodeList),
+            4 => Some(HandleKind::Selector),
+            5 => Some(HandleKind::Cursor),
+            _ => None,
+        }
+    }
+
+    /// How a handle report names the kind.
+    pub(crate) fn key(self) -> &'static str {
+        match self {
+            HandleKind::Document => "document",
+            HandleKind::Node => "node",
+            HandleKind::NodeList => "nodeList",
+            HandleKind::Selector => "selector",
+            HandleKind::Cursor => "cursor",
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            HandleKind::Document => "a document",
+            HandleKind::Node => "a node",
+            HandleKind::NodeList => "a node list",
+            HandleKind::Selector => "a compiled selector",
+            HandleKind::Cursor => "a cursor",
+        }
+    }
+}
+
+// A handle is `kind << 56 | generation << 32 | slot`: always positive, never -1. The
+// low `SHARD_BITS` of the slot pick the shard holding it.
+const KIND_SHIFT: u32 = 56;
+const GENERATION_SHIFT: u32 = 32;
+/// Generations are 24 bits; a slot that reaches the last one is not reused.
+const MAX_GENERATION: u32 = (1 << (KIND_SHIFT - GENERATION_SHIFT)) - 1;
+const SHARDS: usize = 1 << SHARD_BITS;
+
+fn encode(kind: HandleKind, generation: u32, slot: u32) -> i64 {
+    ((kind as i64) << KIND_SHIFT) | ((generation as i64) << GENERATION_SHIFT) | slot as i64
+}
+
+/// The kind, generation and slot of a handle, or `None` if it cannot be one.
+fn decode(handle: i64) -> Option<(HandleKind, u32, u32)> {
+    if handle < 0 {
+        return None;
+    }
+    let kind = HandleKind::from_bits(handle >> KIND_SHIFT)?;
+    let generation = ((handle >> GENERATION_SHIFT) as u32) & MAX_GENERATION;
+    Some((kind, generation, handle as u32))
+}
+
+/// The kind a handle claims to be, without checking that it is live.
+fn kind_of(handle: i64) -> Option<HandleKind> {
+    decode(handle).map(|(kind, _, _)| kind)
+}
+
+struct Slot<T> {
+    /// Bumped each time the slot is vacated, so handles to earlier values go stale.
+    generation: u32,
+    value: Option<T>,
+    /// When the current value was stored.
+    created: Instant,
+    /// The last generation whose value was freed with its document, while the caller
+    /// has not freed its handle since.
+    invalidated: Option<u32>,
+}
+
+/// The values of one kind of handle in one shard, in reusable slots.
+struct Slab<T> {
+    kind: HandleKind,
+    slots: Vec<Slot<T>>,
+    free: Vec<usize>,
+}
+
+impl<T> Slab<T> {
+    const fn new(kind: HandleKind) -> Self {
+        Slab {
+            kind,
+            slots: Vec::new(),
+            free: Vec::new(),
+        }
+    }
+
+    fn insert(&mut self, shard: usize, value: T) -> i64 {
+        let index = match self.free.pop() {
+            Some(index) => index,
+            None => {
+                self.slots.push(Slot {
+                    generation: 0,
+                    value: None,
+                    created: Instant::now(),
+                    invalidated: None,
+                });
+                self.slots.len() - 1
+            }
+        };
+        let entry = &mut self.slots[index];
+        entry.value = Some(value);
+        entry.created = Instant::now();
+        encode(
+            self.kind,
+            entry.generation,
+            ((index << SHARD_BITS) | shard) as u32,
+        )
+    }
+
+    /// The index of the slot `handle` names, if it is live and of this slab's kind.
+    fn find(&self, handle: i64) -> Option<usize> {
+        let (kind, generation, slot) = decode(handle)?;
+        let index = (slot >> SHARD_BITS) as usize;
+        let entry = self.slots.get(index)?;
+        (kind == self.kind && entry.generation == generation && entry.value.is_some())
+            .then_some(index)
+    }
+
+    fn get(&self, handle: i64) -> Option<&T> {
+        self.find(handle)
+            .and_then(|index| self.slots[index].value.as_ref())
+    }
+
+    /// Remove the value `handle` names; `invalidate` if its document is being freed.
+    fn remove(&mut self, handle: i64, invalidate: bool) -> Option<T> {
+        let index = self.find(handle)?;
+        let value = self.slots[index].value.take();
+        self.vacate(index, invalidate);
+        value
+    }
+
+    fn vacate(&mut self, index: usize, invalidate: bool) {
+        let entry = &mut self.slots[index];
+        if invalidate {
+            entry.invalidated = Some(entry.generation);
+        }
+        entry.generation += 1;
+        if entry.generation < MAX_GENERATION {
+            self.free.push(index);
+        }
+    }
+
+    #[cfg(not(feature = "shared-store"))]
+    fn clear(&mut self) {
+        for index in 0..self.slots.len() {
+            if self.slots[index].value.take().is_some() {
+                self.vacate(index, false);
+            }
+            self.slots[index].invalidated = None;
+        }
+    }
+
+    /// The handle and creation time of each live value in this shard.
+    fn live(&self, shard: usize) -> impl Iterator<Item = (i64, Instant)> + '_ {
+        self.slots
+            .iter()
+            .enumerate()
+            .filter_map(move |(index, entry)| {
+                entry.value.as_ref()?;
+                let slot = ((index << SHARD_BITS) | shard) as u32;
+                Some((encode(self.kind, entry.generation, slot), entry.created))
+            })
+    }
+
+    /// The index of the slot `handle` names, if it was freed with its document and not
+    /// by the caller since.
+    fn find_invalidated(&self, handle: i64) -> Option<usize> {
+        let (kind, generation, slot) = decode(handle)?;
+        let index = (slot >> SHARD_BITS) as usize;
+        let entry = self.slots.get(index)?;
+        (kind == self.kind && entry.invalidated == Some(generation)).then_some(index)
+    }
+
+    fn invalidated(&self, handle: i64) -> bool {
+        self.find_invalidated(handle).is_some()
+    }
+
+    /// Record that the caller freed the invalidated `handle`. `false` if it was not
+    /// invalidated.
+    fn acknowledge(&mut self, handle: i64) -> bool {
+        let Some(index) = self.find_invalidated(handle) else {
+            return false;
+        };
+        self.slots[index].invalidated = None;
+        true
+    }
+
+    /// How many handles of this shard are invalidated.
+    fn invalidated_count(&self) -> usize {
+        self.slots
+            .iter()
+            .filter(|entry| entry.invalidated.is_some())
+            .count()
+    }
+
+    /// Whether a handle of this slab's kind was handed out and freed since.
+    fn was_freed(&self, handle: i64) -> bool {
+        decode(handle).is_some_and(|(_, generation, slot)| {
+            self.slots
+                .get((slot >> SHARD_BITS) as usize)
+                .is_some_and(|entry| generation < entry.generation)
+        })
+    }
+}
+
+/// The shards of one kind of handle.
+type Store<T> = Global<[Lock<Slab<T>>; SHARDS]>;
+
+// Without `shared-store` there is one shard, and the modulo is always 0.
+#[allow(clippy::modulo_one)]
+fn shard_index(n: usize) -> usize {
+    n % SHARDS
+}
+
+/// The shard of `store` holding `handle`.
+fn shard<T>(store: &[Lock<Slab<T>>; SHARDS], handle: i64) -> &Lock<Slab<T>> {
+    &store[shard_index(handle as usize)]
+}
+
+sync::stores! {
+    static DOCUMENTS: [Lock<Slab<Shared<Lock<DocEntry>>>>; SHARDS] =
+        [const { Lock::new(Slab::new(HandleKind::Document)) }; SHARDS];
+    static NODES: [Lock<Slab<NodeEntry>>; SHARDS] =
+        [const { Lock::new(Slab::new(HandleKind::Node)) }; SHARDS];
+    static NODE_LISTS: [Lock<Slab<Vec<NodeEntry>>>; SHARDS] =
+        [const { Lock::new(Slab::new(HandleKind::NodeList)) }; SHARDS];
+    static SELECTORS: [Lock<Slab<Arc<JsoupSelector>>>; SHARDS] =
+        [const { Lock::new(Slab::new(HandleKind::Selector)) }; SHARDS];
+    static CURSORS: [Lock<Slab<Shared<Lock<Cursor>>>>; SHARDS] =
+        [const { Lock::new(Slab::new(HandleKind::Cursor)) }; SHARDS];
+    /// The node, node-list and cursor handles pointing into each document, freed with it.
+    static DEPENDENTS: Lock<BTreeMap<i64, HashSet<i64>>> = Lock::new(BTreeMap::new());
+    /// Open sessions by id.
+    static SESSIONS: Lock<BTreeMap<i64, Session>> = Lock::new(BTreeMap::new());
 }
 
-/// Thread-local handle stores.
 thread_local! {
-    static DOCUMENTS: RefCell<HashMap<i64, DocEntry>> = RefCell::new(HashMap::new());
-    static NODES: RefCell<HashMap<i64, NodeEntry>> = RefCell::new(HashMap::new());
-    static NODE_LISTS: RefCell<HashMap<i64, Vec<NodeEntry>>> = RefCell::new(HashMap::new());
+    static LAST_ERROR: RefCell<Option<LastHandleError>> = const { RefCell::new(None) };
+    /// The session handles created on this thread go into, or 0 for none.
+    static CURRENT_SESSION: Cell<i64> = const { Cell::new(0) };
 }
 
-pub fn store_document(html: Html, base_uri: String) -> i64 {
-    let handle = next_handle();
-    DOCUMENTS.with(|docs| {
-        docs.borrow_mut().insert(handle, DocEntry { html, base_uri });
-    });
+/// Spreads new handles over the shards.
+static NEXT_SHARD: AtomicU32 = AtomicU32::new(0);
+static NEXT_SESSION: AtomicI64 = AtomicI64::new(1);
+
+/// Handles created between `scraper_session_begin` and `scraper_session_end`, freed
+/// when the session ends unless promoted out of it first.
+struct Session {
+    /// The session this one was opened inside, and ends with.
+    parent: Option<i64>,
+    handles: HashSet<i64>,
+}
+
+/// What became of a handle, as `scraper_handle_status` reports it.
+#[derive(Clone, Copy)]
+pub(crate) enum HandleStatus {
+    /// Never handed out.
+    Unknown = 0,
+    Live = 1,
+    /// Freed with `scraper_free` or `scraper_release_all`.
+    Freed = 2,
+    /// Freed because the document it points into was. Reported until the caller
+    /// frees the handle too, or a later handle in its slot is freed with its
+    /// document in turn.
+    Invalidated = 3,
+}
+
+/// Why a handle could not be used, as `scraper_handle_error` reports it.
+#[derive(Clone, Copy)]
+pub(crate) enum HandleError {
+    /// Not a handle that was ever handed out.
+    Unknown = 1,
+    /// A live or freed handle of another kind than the function takes.
+    WrongKind = 2,
+    /// Used after `scraper_free` or `scraper_release_all`.
+    Freed = 3,
+    /// Used after the document it points into was freed.
+    Invalidated = 4,
+}
+
+/// The last failed handle lookup on this thread, as reported through the FFI.
+pub(crate) struct LastHandleError {
+    pub error: HandleError,
+    pub message: String,
+}
+
+fn store<T>(store: &'static Store<T>, value: T) -> i64 {
+    let shard = shard_index(NEXT_SHARD.fetch_add(1, Ordering::Relaxed) as usize);
+    let handle = store.with(|shards| lock(&shards[shard]).insert(shard, value));
+    let current = CURRENT_SESSION.with(Cell::get);
+    if current != 0 {
+        SESSIONS.with(|sessions| {
+            if let Some(session) = lock(sessions).get_mut(&current) {
+                session.handles.insert(handle);
+            }
+        });
+    }
     handle
 }
 
-pub fn store_node(entry: NodeEntry) -> i64 {
-    let handle = next_handle();
-    NODES.with(|nodes| {
-        nodes.borrow_mut().insert(handle, entry);
+/// Why `handle` is not live in `slab`.
+fn lookup_error<T>(slab: &Slab<T>, handle: i64) -> LastHandleError {
+    let (error, message) = match kind_of(handle) {
+        None => (HandleError::Unknown, "was never handed out".to_string()),
+        Some(kind) if kind != slab.kind => (
+            HandleError::WrongKind,
+            format!("is {}, not {}", kind.name(), slab.kind.name()),
+        ),
+        Some(_) if slab.invalidated(handle) => (
+            HandleError::Invalidated,
+            "was freed with its document".to_string(),
+        ),
+        Some(_) if slab.was_freed(handle) => (HandleError::Freed, "was freed".to_string()),
+        Some(_) => (HandleError::Unknown, "was never handed out".to_string()),
+    };
+    LastHandleError {
+        error,
+        message: format!("Handle {handle} {message}"),
+    }
+}
+
+/// Record the outcome of looking `handle` up in `slab`, replacing the previous error
+/// (or clearing it on success).
+fn record<T>(slab: &Slab<T>, handle: i64, found: bool) {
+    let error = (!found).then(|| lookup_error(slab, handle));
+    LAST_ERROR.with(|last| *last.borrow_mut() = error);
+}
+
+/// A copy of the value `handle` names in `store`, made under the shard's lock.
+fn get<T: Clone>(store: &'static Store<T>, handle: i64) -> Option<T> {
+    store.with(|shards| {
+        let slab = read(shard(shards, handle));
+        let value = slab.get(handle);
+        record(&slab, handle, value.is_some());
+        value.cloned()
+    })
+}
+
+/// Record that `handle` points into the document `doc_handle`.
+fn depend(handle: i64, doc_handle: i64) {
+    DEPENDENTS.with(|deps| {
+        lock(deps).entry(doc_handle).or_default().insert(handle);
     });
+}
+
+pub fn store_document(html: Html, base_uri: String) -> i64 {
+    store(
+        &DOCUMENTS,
+        Shared::new(Lock::new(DocEntry {
+            html,
+            base_uri,
+            pseudo_text: HashSet::new(),
+            normalization: Normalization::None,
+            tags: BTreeMap::new(),
+            index: OnceCell::new(),
+        })),
+    )
+}
+
+pub fn store_node(entry: NodeEntry) -> i64 {
+    let doc_handle = entry.doc_handle;
+    let handle = store(&NODES, entry);
+    depend(handle, doc_handle);
     handle
 }
 
-pub fn store_node_list(entries: Vec<NodeEntry>) -> i64 {
-    let handle = next_handle();
-    NODE_LISTS.with(|lists| {
-        lists.borrow_mut().insert(handle, entries);
-    });
+/// Store a node list taken from the documents `sources`. It depends on them and on
+/// its entries' documents, so even an empty list is freed with its document.
+pub fn store_node_list(entries: Vec<NodeEntry>, sources: impl IntoIterator<Item = i64>) -> i64 {
+    let doc_handles: HashSet<i64> = sources
+        .into_iter()
+        .chain(entries.iter().map(|entry| entry.doc_handle))
+        .collect();
+    let handle = store(&NODE_LISTS, entries);
+    for doc_handle in doc_handles {
+        depend(handle, doc_handle);
+    }
+    handle
+}
+
+/// The documents `handle` depends on, for a list taken from a node list to depend on
+/// them too.
+pub(crate) fn documents_of(handle: i64) -> Vec<i64> {
+    DEPENDENTS.with(|deps| {
+        read(deps)
+            .iter()
+            .filter(|(_, dependents)| dependents.contains(&handle))
+            .map(|(&doc_handle, _)| doc_handle)
+            .collect()
+    })
+}
+
+pub(crate) fn store_selector(selector: JsoupSelector) -> i64 {
+    store(&SELECTORS, Arc::new(selector))
+}
+
+pub(crate) fn store_cursor(cursor: Cursor) -> i64 {
+    let doc_handle = cursor.doc_handle;
+    let handle = store(&CURSORS, Shared::new(Lock::new(cursor)));
+    depend(handle, doc_handle);
     handle
 }
 
 /// Access a document by handle, calling `f` with a reference.
 pub fn with_doc<R>(handle: i64, f: impl FnOnce(&DocEntry) -> R) -> Option<R> {
-    DOCUMENTS.with(|docs| {
-        let docs = docs.borrow();
-        docs.get(&handle).map(f)
-    })
+    let doc = get(&DOCUMENTS, handle)?;
+    let doc = read(&doc);
+    Some(f(&doc))
 }
 
 /// Access a document by handle mutably.
 pub fn with_doc_mut<R>(handle: i64, f: impl FnOnce(&mut DocEntry) -> R) -> Option<R> {
-    DOCUMENTS.with(|docs| {
-        let mut docs = docs.borrow_mut();
-        docs.get_mut(&handle).map(f)
-    })
+    let doc = get(&DOCUMENTS, handle)?;
+    let mut doc = lock(&doc);
+    Some(f(&mut doc))
 }
 
 /// Get a node entry by handle.
 pub fn get_node(handle: i64) -> Option<NodeEntry> {
-    NODES.with(|nodes| {
-        let nodes = nodes.borrow();
-        nodes.get(&handle).copied()
-    })
+    get(&NODES, handle)
+}
+
+/// Access a document by handle without recording the lookup for
+/// `scraper_handle_error`, for reports that must not disturb it.
+pub(crate) fn peek_doc<R>(handle: i64, f: impl FnOnce(&DocEntry) -> R) -> Option<R> {
+    let doc = DOCUMENTS.with(|shards| read(shard(shards, handle)).get(handle).cloned())?;
+    let doc = read(&doc);
+    Some(f(&doc))
 }
 
 /// Access a node's document and the node entry together.
@@ -97,38 +592,489 @@ pub fn with_node_doc_mut<R>(
 
 /// Get the node list entries by handle.
 pub fn get_node_list(handle: i64) -> Option<Vec<NodeEntry>> {
-    NODE_LISTS.with(|lists| {
-        let lists = lists.borrow();
-        lists.get(&handle).cloned()
+    get(&NODE_LISTS, handle)
+}
+
+/// Get a compiled selector by handle.
+pub(crate) fn get_selector(handle: i64) -> Option<Arc<JsoupSelector>> {
+    get(&SELECTORS, handle)
+}
+
+/// Access a cursor by handle mutably.
+pub(crate) fn with_cursor_mut<R>(handle: i64, f: impl FnOnce(&mut Cursor) -> R) -> Option<R> {
+    let cursor = get(&CURSORS, handle)?;
+    let mut cursor = lock(&cursor);
+    Some(f(&mut cursor))
+}
+
+/// Remove `handle` from `store`, recording the outcome. `invalidate` if its document
+/// is being freed.
+fn remove<T>(store: &'static Store<T>, handle: i64, invalidate: bool) -> Option<T> {
+    store.with(|shards| {
+        let mut slab = lock(shard(shards, handle));
+        let value = slab.remove(handle, invalidate);
+        record(&slab, handle, value.is_some());
+        value
     })
 }
 
-/// Free a handle from any store.
+/// Free a handle of any kind. Freeing a document also frees every node, node list
+/// and cursor pointing into it, which then report `HandleStatus::Invalidated`.
+/// Freeing a handle twice records `HandleError::Freed`.
 pub fn free_handle(handle: i64) {
-    DOCUMENTS.with(|docs| {
-        docs.borrow_mut().remove(&handle);
+    SESSIONS.with(|sessions| {
+        for session in lock(sessions).values_mut() {
+            session.handles.remove(&handle);
+        }
+    });
+    match kind_of(handle) {
+        Some(HandleKind::Document) => {
+            if remove(&DOCUMENTS, handle, false).is_none() {
+                return;
+            }
+            let dependents = DEPENDENTS.with(|deps| lock(deps).remove(&handle));
+            for dependent in dependents.into_iter().flatten() {
+                remove_dependent(dependent, true);
+            }
+        }
+        Some(HandleKind::Selector) => {
+            remove(&SELECTORS, handle, false);
+        }
+        Some(HandleKind::Node) if acknowledge(&NODES, handle) => {}
+        Some(HandleKind::NodeList) if acknowledge(&NODE_LISTS, handle) => {}
+        Some(HandleKind::Cursor) if acknowledge(&CURSORS, handle) => {}
+        _ => remove_dependent(handle, false),
+    }
+}
+
+/// Record that the caller freed `handle` after its document was freed. `false` if
+/// its document was not freed first.
+fn acknowledge<T>(store: &'static Store<T>, handle: i64) -> bool {
+    store.with(|shards| lock(shard(shards, handle)).acknowledge(handle))
+}
+
+/// Remove a node, node-list or cursor handle, and forget it in the documents it
+/// points into. `invalidate` if one of them is being freed.
+fn remove_dependent(handle: i64, invalidate: bool) {
+    let doc_handles: Vec<i64> = match kind_of(handle) {
+        Some(HandleKind::NodeList) => remove(&NODE_LISTS, handle, invalidate)
+            .into_iter()
+            .flatten()
+            .map(|entry| entry.doc_handle)
+            .collect(),
+        Some(HandleKind::Cursor) => remove(&CURSORS, handle, invalidate)
+            .map(|cursor| read(&cursor).doc_handle)
+            .into_iter()
+            .collect(),
+        _ => remove(&NODES, handle, invalidate)
+            .map(|entry| entry.doc_handle)
+            .into_iter()
+            .collect(),
+    };
+    DEPENDENTS.with(|deps| {
+        let mut deps = lock(deps);
+        for doc_handle in doc_handles {
+            if let Some(dependents) = deps.get_mut(&doc_handle) {
+                dependents.remove(&handle);
+            }
+        }
     });
-    NODES.with(|nodes| {
-        nodes.borrow_mut().remove(&handle);
+}
+
+/// The session the calling thread is in, if it is still open.
+fn current_session() -> Option<i64> {
+    let current = CURRENT_SESSION.with(Cell::get);
+    SESSIONS.with(|sessions| lock(sessions).contains_key(&current).then_some(current))
+}
+
+/// Open a session inside the one the calling thread is in, if any, and enter it.
+/// Returns its id.
+pub(crate) fn begin_session() -> i64 {
+    let id = NEXT_SESSION.fetch_add(1, Ordering::Relaxed);
+    let parent = current_session();
+    SESSIONS.with(|sessions| {
+        lock(sessions).insert(
+            id,
+            Session {
+                parent,
+                handles: HashSet::new(),
+            },
+        )
     });
-    NODE_LISTS.with(|lists| {
-        lists.borrow_mut().remove(&handle);
+    CURRENT_SESSION.with(|current| current.set(id));
+    id
+}
+
+/// Make `id` the session the calling thread's handles go into, or leave every
+/// session for 0. Returns the session it was in before (0 for none), or `None` if no
+/// open session has that id.
+pub(crate) fn enter_session(id: i64) -> Option<i64> {
+    if id != 0 && !SESSIONS.with(|sessions| lock(sessions).contains_key(&id)) {
+        return None;
+    }
+    Some(CURRENT_SESSION.with(|current| current.replace(id)))
+}
+
+/// End session `id` and any still open inside it, freeing every handle they hold.
+/// Any thread can end any session. `false` if no open session has that id.
+pub(crate) fn end_session(id: i64) -> bool {
+    let ended = SESSIONS.with(|sessions| {
+        let mut sessions = lock(sessions);
+        let parent = sessions.get(&id)?.parent;
+        let inside = |mut at: i64| loop {
+            if at == id {
+                return true;
+            }
+            match sessions.get(&at).and_then(|session| session.parent) {
+                Some(parent) => at = parent,
+                None => return false,
+            }
+        };
+        // Sessions open inside another have later ids, so this ends the innermost first.
+        let ids: Vec<i64> = sessions
+            .keys()
+            .rev()
+            .copied()
+            .filter(|&at| inside(at))
+            .collect();
+        let ended: Vec<Session> = ids.iter().filter_map(|at| sessions.remove(at)).collect();
+        Some((parent, ended))
     });
+    let Some((parent, ended)) = ended else {
+        return false;
+    };
+    if current_session().is_none() {
+        // The calling thread was in one of the ended sessions: go back out of them.
+        CURRENT_SESSION.with(|current| current.set(parent.unwrap_or(0)));
+    }
+    for session in ended {
+        for handle in session.handles {
+            // Another thread may have freed it already.
+            if matches!(
+                handle_status(handle),
+                HandleStatus::Live | HandleStatus::Invalidated
+            ) {
+                free_handle(handle);
+            }
+        }
+    }
+    true
 }
 
-/// Release all handles.
+/// Move `handle` from the session holding it to the one around that, or out of every
+/// session if there is none. `false` if no session holds it.
+pub(crate) fn promote(handle: i64) -> bool {
+    SESSIONS.with(|sessions| {
+        let mut sessions = lock(sessions);
+        let Some(parent) = sessions
+            .values_mut()
+            .find_map(|session| session.handles.remove(&handle).then_some(session.parent))
+        else {
+            return false;
+        };
+        if let Some(outer) = parent.and_then(|parent| sessions.get_mut(&parent)) {
+            outer.handles.insert(handle);
+        }
+        true
+    })
+}
+
+#[cfg(not(feature = "shared-store"))]
+fn clear<T>(store: &'static Store<T>) {
+    store.with(|shards| shards.iter().for_each(|slab| lock(slab).clear()));
+}
+
+/// Release all handles, and end every session.
+#[cfg(not(feature = "shared-store"))]
 pub fn release_all() {
-    DOCUMENTS.with(|docs| docs.borrow_mut().clear());
-    NODES.with(|nodes| nodes.borrow_mut().clear());
-    NODE_LISTS.with(|lists| lists.borrow_mut().clear());
+    SESSIONS.with(|sessions| lock(sessions).clear());
+    CURRENT_SESSION.with(|current| current.set(0));
+    clear(&DOCUMENTS);
+    clear(&NODES);
+    clear(&NODE_LISTS);
+    clear(&SELECTORS);
+    clear(&CURSORS);
+    DEPENDENTS.with(|deps| lock(deps).clear());
+}
+
+/// End the outermost session around the one the calling thread is in, freeing what
+/// it holds. Other isolates' handles share the store, so nothing else is released.
+#[cfg(feature = "shared-store")]
+pub fn release_all() {
+    let Some(mut outermost) = current_session() else {
+        return;
+    };
+    while let Some(parent) =
+        SESSIONS.with(|sessions| lock(sessions).get(&outermost).and_then(|s| s.parent))
+    {
+        outermost = parent;
+    }
+    end_session(outermost);
+}
+
+fn status_in<T>(store: &'static Store<T>, handle: i64) -> HandleStatus {
+    store.with(|shards| {
+        let slab = read(shard(shards, handle));
+        if slab.find(handle).is_some() {
+            HandleStatus::Live
+        } else if slab.invalidated(handle) {
+            HandleStatus::Invalidated
+        } else if slab.was_freed(handle) {
+            HandleStatus::Freed
+        } else {
+            HandleStatus::Unknown
+        }
+    })
+}
+
+/// What became of `handle`.
+pub(crate) fn handle_status(handle: i64) -> HandleStatus {
+    match kind_of(handle) {
+        Some(HandleKind::Document) => status_in(&DOCUMENTS, handle),
+        Some(HandleKind::Node) => status_in(&NODES, handle),
+        Some(HandleKind::NodeList) => status_in(&NODE_LISTS, handle),
+        Some(HandleKind::Selector) => status_in(&SELECTORS, handle),
+        Some(HandleKind::Cursor) => status_in(&CURSORS, handle),
+        None => HandleStatus::Unknown,
+    }
+}
+
+/// A live handle, as `scraper_handle_report` lists it.
+pub(crate) struct LiveHandle {
+    pub handle: i64,
+    pub kind: HandleKind,
+    pub created: Instant,
+    /// The documents a node, node list or cursor points into.
+    pub documents: Vec<i64>,
+}
+
+fn live_in<T>(store: &'static Store<T>, kind: HandleKind, out: &mut Vec<LiveHandle>) {
+    store.with(|shards| {
+        for (shard, slab) in shards.iter().enumerate() {
+            out.extend(read(slab).live(shard).map(|(handle, created)| LiveHandle {
+                handle,
+                kind,
+                created,
+                documents: Vec::new(),
+            }));
+        }
+    });
+}
+
+/// Every live handle, oldest first.
+pub(crate) fn live_handles() -> Vec<LiveHandle> {
+    let mut live = Vec::new();
+    live_in(&DOCUMENTS, HandleKind::Document, &mut live);
+    live_in(&NODES, HandleKind::Node, &mut live);
+    live_in(&NODE_LISTS, HandleKind::NodeList, &mut live);
+    live_in(&SELECTORS, HandleKind::Selector, &mut live);
+    live_in(&CURSORS, HandleKind::Cursor, &mut live);
+    let mut documents: HashMap<i64, Vec<i64>> = HashMap::new();
+    DEPENDENTS.with(|deps| {
+        for (&doc_handle, dependents) in read(deps).iter() {
+            for &dependent in dependents {
+                documents.entry(dependent).or_default().push(doc_handle);
+            }
+        }
+    });
+    for handle in &mut live {
+        handle.documents = documents.remove(&handle.handle).unwrap_or_default();
+    }
+    live.sort_by_key(|handle| handle.created);
+    live
 }
 
-/// Check if a handle is a document.
+/// How many handles were freed with their document but not by the caller.
+pub(crate) fn invalidated_count() -> usize {
+    fn count<T>(store: &'static Store<T>) -> usize {
+        store.with(|shards| {
+            shards
+                .iter()
+                .map(|slab| read(slab).invalidated_count())
+                .sum()
+        })
+    }
+    count(&NODES) + count(&NODE_LISTS) + count(&CURSORS)
+}
+
+/// How many sessions are open.
+pub(crate) fn open_sessions() -> usize {
+    SESSIONS.with(|sessions| read(sessions).len())
+}
+
+/// Access the last handle error, if the last lookup on this thread failed.
+pub(crate) fn with_last_error<R>(f: impl FnOnce(&LastHandleError) -> R) -> Option<R> {
+    LAST_ERROR.with(|last| last.borrow().as_ref().map(f))
+}
+
+/// Check if a handle names a document. Only its kind is checked: using a freed one
+/// fails in the lookup, which records why.
 pub fn is_document(handle: i64) -> bool {
-    DOCUMENTS.with(|docs| docs.borrow().contains_key(&handle))
+    kind_of(handle) == Some(HandleKind::Document)
+}
+
+/// Check if a handle names a node list, by its kind only as `is_document` does.
+pub(crate) fn is_node_list(handle: i64) -> bool {
+    kind_of(handle) == Some(HandleKind::NodeList)
 }
 
-/// Check if a handle is in the node list store.
-pub fn is_node_list(handle: i64) -> bool {
-    NODE_LISTS.with(|lists| lists.borrow().contains_key(&handle))
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn doc_with_node() -> (i64, i64) {
+        let html = Html::parse_document("<p>x</p>");
+        let node_id = html.root_element().id();
+        let doc = store_document(html, String::new());
+        let node = store_node(NodeEntry {
+            node_id,
+            doc_handle: doc,
+            is_text: false,
+            attr: None,
+        });
+        (doc, node)
+    }
+
+    #[test]
+    fn invalidated_until_freed_by_the_caller() {
+        let (doc, node) = doc_with_node();
+        free_handle(doc);
+        assert!(matches!(handle_status(node), HandleStatus::Invalidated));
+        assert!(get_node(node).is_none());
+        assert!(with_last_error(|last| matches!(last.error, HandleError::Invalidated)).unwrap());
+        free_handle(node);
+        assert!(matches!(handle_status(node), HandleStatus::Freed));
+    }
+
+    // The count is process-wide with `shared-store`, so other tests would move it.
+    #[cfg(not(feature = "shared-store"))]
+    #[test]
+    fn invalidated_handles_do_not_accumulate() {
+        for _ in 0..100 {
+            let (doc, _) = doc_with_node();
+            free_handle(doc);
+        }
+        assert_eq!(invalidated_count(), 1);
+    }
+
+    #[test]
+    fn sessions_nest_and_end_with_their_parent() {
+        let (outside, _) = doc_with_node();
+        let outer = begin_session();
+        let (outer_doc, _) = doc_with_node();
+        let inner = begin_session();
+        let (inner_doc, inner_node) = doc_with_node();
+        assert_eq!(current_session(), Some(inner));
+
+        assert!(end_session(outer));
+        assert_eq!(current_session(), None);
+        for handle in [outer_doc, inner_doc, inner_node] {
+            assert!(matches!(handle_status(handle), HandleStatus::Freed));
+        }
+        assert!(matches!(handle_status(outside), HandleStatus::Live));
+        assert!(!end_session(inner));
+        assert!(!end_session(outer));
+        assert_eq!(enter_session(inner), None);
+        free_handle(outside);
+    }
+
+    #[test]
+    fn promoted_handles_move_out_one_session() {
+        let outer = begin_session();
+        let inner = begin_session();
+        let (doc, node) = doc_with_node();
+        assert!(promote(doc));
+        assert!(promote(node));
+        assert!(promote(node));
+        assert!(!promote(node));
+
+        assert!(end_session(inner));
+        assert!(matches!(handle_status(doc), HandleStatus::Live));
+        assert!(matches!(handle_status(node), HandleStatus::Live));
+        assert!(end_session(outer));
+        // The node outlived every session, but not its document.
+        assert!(matches!(handle_status(doc), HandleStatus::Freed));
+        assert!(matches!(handle_status(node), HandleStatus::Invalidated));
+        free_handle(node);
+    }
+
+    #[test]
+    fn entering_sessions() {
+        let first = begin_session();
+        let second = begin_session();
+        assert_eq!(enter_session(first), Some(second));
+        let (doc, _) = doc_with_node();
+        assert_eq!(enter_session(0), Some(first));
+        let (outside, _) = doc_with_node();
+        assert_eq!(enter_session(-7), None);
+        assert_eq!(current_session(), None);
+
+        assert!(end_session(first));
+        assert!(matches!(handle_status(doc), HandleStatus::Freed));
+        assert!(matches!(handle_status(outside), HandleStatus::Live));
+        free_handle(outside);
+    }
+
+    #[cfg(not(feature = "shared-store"))]
+    #[test]
+    fn release_all_ends_every_session() {
+        let session = begin_session();
+        begin_session();
+        let (doc, _) = doc_with_node();
+        release_all();
+        assert!(matches!(handle_status(doc), HandleStatus::Freed));
+        assert_eq!(open_sessions(), 0);
+        assert!(!end_session(session));
+    }
+
+    #[cfg(feature = "shared-store")]
+    #[test]
+    fn sessions_are_shared_between_threads() {
+        use std::thread;
+
+        let session = begin_session();
+        let (doc, node) = doc_with_node();
+        // Another thread neither adds to the session nor is kept from ending it.
+        let outside = thread::spawn(move || {
+            let (outside, _) = doc_with_node();
+            assert!(end_session(session));
+            outside
+        })
+        .join()
+        .unwrap();
+        assert!(matches!(handle_status(doc), HandleStatus::Freed));
+        assert!(matches!(handle_status(node), HandleStatus::Freed));
+        assert!(matches!(handle_status(outside), HandleStatus::Live));
+        assert!(!end_session(session));
+
+        // A thread that enters the session adds to it.
+        let session = begin_session();
+        assert_eq!(enter_session(0), Some(session));
+        let entered = thread::spawn(move || {
+            assert_eq!(enter_session(session), Some(0));
+            doc_with_node().0
+        })
+        .join()
+        .unwrap();
+        assert!(end_session(session));
+        assert!(matches!(handle_status(entered), HandleStatus::Freed));
+        assert_eq!(enter_session(session), None);
+        free_handle(outside);
+    }
+
+    #[cfg(feature = "shared-store")]
+    #[test]
+    fn release_all_only_ends_the_callers_sessions() {
+        let other = std::thread::spawn(|| doc_with_node().0).join().unwrap();
+        let outer = begin_session();
+        let (outer_doc, _) = doc_with_node();
+        begin_session();
+        let (inner_doc, _) = doc_with_node();
+        release_all();
+        assert!(matches!(handle_status(outer_doc), HandleStatus::Freed));
+        assert!(matches!(handle_status(inner_doc), HandleStatus::Freed));
+        assert!(!end_session(outer));
+        assert!(matches!(handle_status(other), HandleStatus::Live));
+        free_handle(other);
+    }
 }
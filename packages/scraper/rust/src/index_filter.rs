@@ -0,0 +1,59 @@
+use crate::selector_error::SelectorError;
+use crate::tokenizer::Part;
+use ego_tree::NodeId;
+use scraper::ElementRef;
+
+/// Parsed Jsoup sibling-index pseudo-selector (`:eq(n)`, `:lt(n)`, `:gt(n)`).
+pub(crate) struct IndexFilter {
+    pub index: usize,
+    pub kind: IndexKind,
+}
+
+#[derive(Clone, Copy)]
+pub(crate) enum IndexKind {
+    /// :eq(n) — element sibling index equals n
+    Equals,
+    /// :lt(n) — element sibling index is less than n (never matches the select root)
+    LessThan,
+    /// :gt(n) — element sibling index is greater than n
+    GreaterThan,
+}
+
+const INDEX_PSEUDOS: &[(&str, IndexKind)] = &[
+    ("eq", IndexKind::Equals),
+    ("lt", IndexKind::LessThan),
+    ("gt", IndexKind::GreaterThan),
+];
+
+/// Zero-based position of `el` among its parent's element children, as Jsoup's
+/// `Element.elementSiblingIndex()`. An element without a parent has index 0.
+pub(crate) fn element_sibling_index(el: &ElementRef) -> usize {
+    el.prev_siblings()
+        .filter(|s| s.value().is_element())
+        .count()
+}
+
+/// Parse `part` if it is an index pseudo-selector. Fails if the index is not a
+/// non-negative integer.
+pub(crate) fn parse_index<'a>(part: &Part<'a>) -> Option<Result<IndexFilter, SelectorError<'a>>> {
+    INDEX_PSEUDOS.iter().find_map(|&(name, kind)| {
+        part.pseudo_arg(name).map(|arg| {
+            let index = arg
+                .trim()
+                .parse::<usize>()
+                .map_err(|_| SelectorError::new("Index must be numeric", arg))?;
+            Ok(IndexFilter { index, kind })
+        })
+    })
+}
+
+/// Check if an element matches an index filter. `root` is the element the select
+/// was run on, if any.
+pub(crate) fn matches_index(filter: &IndexFilter, el: &ElementRef, root: Option<NodeId>) -> bool {
+    let idx = element_sibling_index(el);
+    match filter.kind {
+        IndexKind::Equals => idx == filter.index,
+        IndexKind::LessThan => root != Some(el.id()) && idx < filter.index,
+        IndexKind::GreaterThan => idx > filter.index,
+    }
+}
@@ -0,0 +1,511 @@
+use std::iter::Peekable;
+use std::str::Chars;
+
+/// A Jsoup (`java.util.regex`) pattern compiled for `Matcher.find()`-style matching.
+pub(crate) enum JavaRegex {
+    /// Linear-time engine, used whenever it accepts the pattern.
+    Linear(regex::Regex),
+    /// Backtracking engine for what the linear one rejects: lookaround,
+    /// backreferences, atomic groups and possessive quantifiers.
+    Backtracking(fancy_regex::Regex),
+}
+
+impl JavaRegex {
+    /// Compile a Java regex, returning the engine's error message if neither accepts it.
+    pub(crate) fn new(pattern: &str) -> Result<Self, String> {
+        if let Ok(re) = regex::Regex::new(&translate(pattern, true)) {
+            return Ok(JavaRegex::Linear(re));
+        }
+        fancy_regex::Regex::new(&translate(pattern, false))
+            .map(JavaRegex::Backtracking)
+            .map_err(|e| e.to_string())
+    }
+
+    /// Whether the pattern matches anywhere in `text`.
+    pub(crate) fn is_match(&self, text: &str) -> bool {
+        match self {
+            JavaRegex::Linear(re) => re.is_match(text),
+            // Exceeding the backtrack limit counts as no match, as a timeout would in Java.
+            JavaRegex::Backtracking(re) => re.is_match(text).unwrap_or(false),
+        }
+    }
+}
+
+// Java's `\d`, `\w` and `\s` are ASCII-only, as class contents.
+const DIGIT: &str = "0-9";
+const WORD: &str = "0-9A-Za-z_";
+const SPACE: &str = r"\t\n\x0B\x0C\r ";
+// Java's `\h` and `\v`, which the Rust engines lack or read as `\x0B` alone.
+const HSPACE: &str = r"\t \xA0\x{1680}\x{180E}\x{2000}-\x{200A}\x{202F}\x{205F}\x{3000}";
+const VSPACE: &str = r"\n\x0B\x0C\r\x{85}\x{2028}\x{2029}";
+/// Java's `\R`, any line break.
+const LINEBREAK: &str = r"(?:\r\n|[\n\x0B\x0C\r\x{85}\x{2028}\x{2029}])";
+
+/// `\b` and `\B` over ASCII word characters, for the engine without `(?-u:\b)`.
+const BOUNDARY: &str = "(?:(?<=[0-9A-Za-z_])(?![0-9A-Za-z_])|(?<![0-9A-Za-z_])(?=[0-9A-Za-z_]))";
+const NOT_BOUNDARY: &str =
+    "(?:(?<=[0-9A-Za-z_])(?=[0-9A-Za-z_])|(?<![0-9A-Za-z_])(?![0-9A-Za-z_]))";
+
+/// The Java flags that change how a pattern is translated.
+#[derive(Clone, Copy, Default)]
+struct Flags {
+    /// `(?i)`, which only folds ASCII letters unless `unicode_case` is also set.
+    fold: bool,
+    /// `(?u)`, UNICODE_CASE.
+    unicode_case: bool,
+    /// `(?U)`, UNICODE_CHARACTER_CLASS: Unicode `\d`, `\w`, `\s` and `\b`, as the
+    /// Rust engines have them by default.
+    unicode_classes: bool,
+}
+
+impl Flags {
+    fn ascii_fold(self) -> bool {
+        self.fold && !self.unicode_case
+    }
+}
+
+/// Rewrites a Java pattern into the Rust engines' syntax with Java's meaning.
+struct Translator<'a> {
+    chars: Peekable<Chars<'a>>,
+    out: String,
+    flags: Flags,
+    /// The flags to restore at the end of each open group.
+    saved: Vec<Flags>,
+    /// Whether the output is for the `regex` crate rather than `fancy_regex`.
+    linear: bool,
+}
+
+/// Translate a Java pattern for one of the engines: `\d`, `\w`, `\s` and `\b` become
+/// ASCII-only and `(?i)` folds only ASCII letters, as in Java; Java's `u`, `U` and
+/// `d` flags are dropped; `\h`, `\v`, `\R` and the `\p{javaLowerCase}` family are
+/// spelled out; and `\Q...\E` quoting, which neither engine supports, becomes
+/// escaped literals.
+fn translate(pattern: &str, linear: bool) -> String {
+    let mut t = Translator {
+        chars: pattern.chars().peekable(),
+        out: String::with_capacity(pattern.len()),
+        flags: Flags::default(),
+        saved: Vec::new(),
+        linear,
+    };
+    while let Some(c) = t.chars.next() {
+        match c {
+            '\\' => t.escape(),
+            '[' => t.class(),
+            '(' => t.group(),
+            ')' => {
+                if let Some(flags) = t.saved.pop() {
+                    t.flags = flags;
+                }
+                t.out.push(')');
+            }
+            c => t.literal(c),
+        }
+    }
+    t.out
+}
+
+impl Translator<'_> {
+    /// A literal character. Under ASCII-only `(?i)`, characters the Rust engines
+    /// would fold beyond ASCII (`k` to the Kelvin sign, `s` to long s, and every
+    /// non-ASCII letter) are matched exactly instead.
+    fn literal(&mut self, c: char) {
+        if !self.flags.ascii_fold() || (c.is_ascii() && !matches!(c, 'k' | 'K' | 's' | 'S')) {
+            self.out.push(c);
+        } else if c.is_ascii() {
+            self.out.push_str("(?-i:[");
+            self.out.push(c.to_ascii_lowercase());
+            self.out.push(c.to_ascii_uppercase());
+            self.out.push_str("])");
+        } else {
+            self.out.push_str("(?-i:");
+            self.out.push(c);
+            self.out.push(')');
+        }
+    }
+
+    /// Copy a `{...}` or `<...>` argument of an escape or group unchanged.
+    fn copy_argument(&mut self, close: char) {
+        for c in self.chars.by_ref() {
+            self.out.push(c);
+            if c == close {
+                break;
+            }
+        }
+    }
+
+    /// The characters of a `\Q...\E` run, up to `\E` or the end of the pattern.
+    fn quoted(&mut self) -> Vec<char> {
+        let mut quoted = Vec::new();
+        while let Some(c) = self.chars.next() {
+            if c == '\\' && self.chars.peek() == Some(&'E') {
+                self.chars.next();
+                break;
+            }
+            quoted.push(c);
+        }
+        quoted
+    }
+
+    fn escape(&mut self) {
+        let Some(c) = self.chars.next() else {
+            self.out.push('\\');
+            return;
+        };
+        let ascii = !self.flags.unicode_classes;
+        match c {
+            'Q' => {
+                for c in self.quoted() {
+                    if c.is_ascii() && regex_syntax_meta(c) {
+                        self.out.push('\\');
+                        self.out.push(c);
+                    } else {
+                        self.literal(c);
+                    }
+                }
+            }
+            'd' | 'w' | 's' if ascii => {
+                self.out.push('[');
+                self.out.push_str(class_contents(c));
+                self.out.push(']');
+            }
+            'D' | 'W' | 'S' if ascii => {
+                self.out.push_str("[^");
+                self.out.push_str(class_contents(c.to_ascii_lowercase()));
+                self.out.push(']');
+            }
+            'b' | 'B' if ascii => self.out.push_str(match (c, self.linear) {
+                ('b', true) => r"(?-u:\b)",
+                (_, true) => r"(?-u:\B)",
+                ('b', false) => BOUNDARY,
+                (_, false) => NOT_BOUNDARY,
+            }),
+            'R' => self.out.push_str(LINEBREAK),
+            c => {
+                if let Some((negated, contents)) = self.java_class(c) {
+                    self.out.push_str(if negated { "[^" } else { "[" });
+                    self.out.push_str(contents);
+                    self.out.push(']');
+                    return;
+                }
+                self.out.push('\\');
+                self.out.push(c);
+                // `\p{Lu}`, `\x{41}` and `\k<name>` name things that must not be folded.
+                match self.chars.peek() {
+                    Some('{') if c.is_ascii_alphabetic() => self.copy_argument('}'),
+                    Some('<') if c == 'k' => self.copy_argument('>'),
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    /// A group, tracking the flags it sets or scopes.
+    fn group(&mut self) {
+        self.out.push('(');
+        if self.chars.peek() != Some(&'?') {
+            self.saved.push(self.flags);
+            return;
+        }
+        let mut ahead = self.chars.clone();
+        ahead.next();
+        let spec: String = ahead
+            .by_ref()
+            .take_while(|c| c.is_ascii_alphabetic() || *c == '-')
+            .collect();
+        // `take_while` consumed the character that ended the flags.
+        let end = self.chars.clone().nth(spec.len() + 1);
+        let is_flags = !spec.is_empty() && matches!(end, Some(')' | ':'));
+        if !is_flags {
+            self.saved.push(self.flags);
+            self.out.push('?');
+            self.chars.next();
+            // A named group: the name is copied as written.
+            let mut rest = self.chars.clone();
+            if rest.next() == Some('<') && rest.next().is_some_and(|c| c.is_ascii_alphabetic()) {
+                self.copy_argument('>');
+            }
+            return;
+        }
+        for _ in 0..spec.len() + 2 {
+            self.chars.next();
+        }
+        let scoped = end == Some(':');
+        if scoped {
+            self.saved.push(self.flags);
+        }
+        let (mut on, mut off) = (String::new(), String::new());
+        let mut enable = true;
+        for flag in spec.chars() {
+            match flag {
+                '-' => enable = false,
+                'i' => self.flags.fold = enable,
+                'u' => self.flags.unicode_case = enable,
+                'U' => self.flags.unicode_classes = enable,
+                _ => {}
+            }
+            // The Rust engines read `u` and `U` differently and have no `d`.
+            if matches!(flag, 'i' | 'm' | 's' | 'x') {
+                if enable { &mut on } else { &mut off }.push(flag);
+            }
+        }
+        self.out.push('?');
+        self.out.push_str(&on);
+        if !off.is_empty() {
+            self.out.push('-');
+            self.out.push_str(&off);
+        }
+        if scoped {
+            self.out.push(':');
+        } else if on.is_empty() && off.is_empty() {
+            // Nothing left to set: drop the group, and the `(` already written.
+            self.out.truncate(self.out.len() - 2);
+        } else {
+            self.out.push(')');
+        }
+    }
+
+    /// A character class. Under ASCII-only `(?i)`, ASCII letters get their other
+    /// case added and the class is matched without Unicode folding.
+    fn class(&mut self) {
+        let fold = self.flags.ascii_fold();
+        if fold {
+            self.out.push_str("(?-i:");
+        }
+        self.class_body(fold);
+        if fold {
+            self.out.push(')');
+        }
+    }
+
+    fn class_body(&mut self, fold: bool) {
+        self.out.push('[');
+        if self.chars.peek() == Some(&'^') {
+            self.chars.next();
+            self.out.push('^');
+        }
+        while let Some(c) = self.chars.next() {
+            match c {
+                ']' => {
+                    self.out.push(']');
+                    return;
+                }
+                '[' => self.class_body(fold),
+                '\\' => self.class_escape(fold),
+                c => {
+                    let mut ahead = self.chars.clone();
+                    match (ahead.next(), ahead.next()) {
+                        (Some('-'), Some(hi)) if !matches!(hi, ']' | '[' | '\\') => {
+                            self.chars.next();
+                            self.chars.next();
+                            self.range(c, hi, fold);
+                        }
+                        _ => self.range(c, c, fold),
+                    }
+                }
+            }
+        }
+    }
+
+    fn class_escape(&mut self, fold: bool) {
+        let Some(c) = self.chars.next() else {
+            self.out.push('\\');
+            return;
+        };
+        let ascii = !self.flags.unicode_classes;
+        match c {
+            'Q' => {
+                for c in self.quoted() {
+                    if regex_syntax_meta(c) || matches!(c, '-' | '&' | '~') {
+                        self.out.push('\\');
+                        self.out.push(c);
+                    } else {
+                        self.range(c, c, fold);
+                    }
+                }
+            }
+            'd' | 'w' | 's' if ascii => self.out.push_str(class_contents(c)),
+            'D' | 'W' | 'S' if ascii => {
+                self.out.push_str("[^");
+                self.out.push_str(class_contents(c.to_ascii_lowercase()));
+                self.out.push(']');
+            }
+            c => {
+                if let Some((negated, contents)) = self.java_class(c) {
+                    if negated {
+                        self.out.push_str("[^");
+                        self.out.push_str(contents);
+                        self.out.push(']');
+                    } else {
+                        self.out.push_str(contents);
+                    }
+                    return;
+                }
+                self.out.push('\\');
+                self.out.push(c);
+                if c.is_ascii_alphabetic() && self.chars.peek() == Some(&'{') {
+                    self.copy_argument('}');
+                }
+            }
+        }
+    }
+
+    /// The class escape `\c` as class contents, and whether it is negated, if the
+    /// Rust engines lack it or read it differently: `\h`, `\v` and the
+    /// `\p{javaLowerCase}` family. Consumes a property's `{name}`.
+    fn java_class(&mut self, c: char) -> Option<(bool, &'static str)> {
+        match c {
+            'h' | 'H' => return Some((c == 'H', HSPACE)),
+            'v' | 'V' => return Some((c == 'V', VSPACE)),
+            'p' | 'P' => {}
+            _ => return None,
+        }
+        let mut ahead = self.chars.clone();
+        if ahead.next() != Some('{') {
+            return None;
+        }
+        let name: String = ahead.take_while(|&c| c != '}').collect();
+        let contents = java_property(&name)?;
+        for _ in 0..name.chars().count() + 2 {
+            self.chars.next();
+        }
+        Some((c == 'P', contents))
+    }
+
+    /// The class member `lo-hi`, with the other case of its ASCII letters if `fold`.
+    fn range(&mut self, lo: char, hi: char, fold: bool) {
+        self.push_range(lo, hi);
+        if !fold {
+            return;
+        }
+        for (from, to) in [('a', 'z'), ('A', 'Z')] {
+            let (l, h) = (lo.max(from), hi.min(to));
+            if l <= h {
+                self.push_range(swap_case(l), swap_case(h));
+            }
+        }
+    }
+
+    fn push_range(&mut self, lo: char, hi: char) {
+        self.out.push(lo);
+        if lo != hi {
+            self.out.push('-');
+            self.out.push(hi);
+        }
+    }
+}
+
+fn class_contents(c: char) -> &'static str {
+    match c {
+        'd' => DIGIT,
+        'w' => WORD,
+        _ => SPACE,
+    }
+}
+
+/// The class contents of a `java.lang.Character` property, as `\p{javaName}`
+/// names it. Properties not listed here are left for the engine to reject.
+fn java_property(name: &str) -> Option<&'static str> {
+    Some(match name {
+        "javaLowerCase" => r"\p{Lowercase}",
+        "javaUpperCase" => r"\p{Uppercase}",
+        "javaTitleCase" => r"\p{Lt}",
+        "javaDigit" => r"\p{Nd}",
+        "javaLetter" => r"\p{L}",
+        "javaLetterOrDigit" => r"\p{L}\p{Nd}",
+        "javaAlphabetic" => r"\p{Alphabetic}",
+        "javaIdeographic" => r"\p{Ideographic}",
+        "javaDefined" => r"\P{Cn}",
+        "javaMirrored" => r"\p{Bidi_Mirrored}",
+        "javaSpaceChar" => r"\p{Zs}\p{Zl}\p{Zp}",
+        // Separators other than no-break spaces, and the ASCII controls Java counts.
+        "javaWhitespace" => {
+            r"\t-\r\x1C-\x20\x{1680}\x{2000}-\x{2006}\x{2008}-\x{200A}\x{2028}\x{2029}\x{205F}\x{3000}"
+        }
+        "javaISOControl" => r"\x00-\x1F\x7F-\x{9F}",
+        _ => return None,
+    })
+}
+
+fn swap_case(c: char) -> char {
+    if c.is_ascii_lowercase() {
+        c.to_ascii_uppercase()
+    } else {
+        c.to_ascii_lowercase()
+    }
+}
+
+/// Whether `c` has to be escaped to match itself.
+fn regex_syntax_meta(c: char) -> bool {
+    regex::escape(c.encode_utf8(&mut [0; 4])).len() > c.len_utf8()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::JavaRegex;
+
+    fn is_match(pattern: &str, text: &str) -> bool {
+        JavaRegex::new(pattern).unwrap().is_match(text)
+    }
+
+    #[test]
+    fn class_escapes_are_ascii() {
+        assert!(is_match(r"^\d+$", "12"));
+        assert!(!is_match(r"^\d+$", "１２"));
+        assert!(!is_match(r"^[\d]+$", "１２"));
+        assert!(!is_match(r"^\w$", "é"));
+        assert!(is_match(r"^\W$", "é"));
+        assert!(!is_match(r"\s", "\u{3000}"));
+        assert!(is_match(r"(?U)^\d+$", "１２"));
+    }
+
+    #[test]
+    fn word_boundaries_are_ascii() {
+        assert!(is_match(r"\bfoo\b", "éfooé"));
+        assert!(!is_match(r"\bfoo\b", "xfoo"));
+        // Lookbehind needs the backtracking engine.
+        assert!(is_match(r"(?<=é)\bfoo\B", "éfoox"));
+        assert!(!is_match(r"(?<=x)\bfoo", "xfoo"));
+    }
+
+    #[test]
+    fn case_insensitivity_is_ascii() {
+        assert!(is_match("(?i)^ABC$", "abc"));
+        assert!(is_match("(?i)^[a-c]+$", "BCA"));
+        assert!(!is_match("(?i)é", "É"));
+        assert!(!is_match("(?i)k", "\u{212A}"));
+        assert!(!is_match("(?i)[s]", "\u{17F}"));
+        assert!(is_match("(?iu)é", "É"));
+        assert!(is_match("(?i:a)b", "Ab"));
+        assert!(!is_match("(?i:a)b", "AB"));
+        assert!(is_match(r"(?i)(?<sk>k)\k<sk>", "kk"));
+        assert!(is_match(r"(?i)\p{Lu}", "A"));
+    }
+
+    #[test]
+    fn java_only_escapes() {
+        assert!(is_match(r"^a\hb$", "a\u{A0}b"));
+        assert!(!is_match(r"\h", "h"));
+        assert!(is_match(r"^\H+$", "ab\n"));
+        assert!(is_match(r"^[\h]$", "\u{3000}"));
+        assert!(is_match(r"^\v$", "\u{2028}"));
+        assert!(!is_match(r"\V", "\n\r"));
+        assert!(is_match(r"^a\Rb$", "a\r\nb"));
+        assert!(is_match(r"^a\Rb$", "a\u{85}b"));
+        assert!(!is_match(r"\R", "R"));
+        assert!(is_match(r"^\p{javaLowerCase}+$", "straße"));
+        assert!(!is_match(r"\p{javaLowerCase}", "ABC"));
+        assert!(is_match(r"^[\p{javaUpperCase}\d]+$", "É1"));
+        assert!(is_match(r"^\P{javaUpperCase}$", "é"));
+        assert!(is_match(r"^\p{javaWhitespace}$", "\u{1F}"));
+        assert!(!is_match(r"\p{javaWhitespace}", "\u{A0}"));
+        assert!(JavaRegex::new(r"\p{javaNoSuchThing}").is_err());
+    }
+
+    #[test]
+    fn quoting() {
+        assert!(is_match(r"^\Q1.5*\E$", "1.5*"));
+        assert!(!is_match(r"^\Q1.5\E$", "125"));
+        assert!(is_match(r"\Qa.b", "a.b"));
+    }
+}
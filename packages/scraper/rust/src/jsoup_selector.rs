@@ -0,0 +1,1235 @@
+use crate::attr_filter::{self, AttrFilter};
+use crate::contains_filter::{self, ContainsFilter, TextCache};
+use crate::element_index::{self, IndexKey};
+use crate::explain::{ChainReport, StepReport};
+use crate::handle_store::DocEntry;
+use crate::index_filter::{self, IndexFilter};
+use crate::normalization::Normalization;
+use crate::pseudo_element::{self, Projection};
+use crate::pseudo_registry::{self, PseudoClass};
+use crate::selector_error::{offset_in, SelectorError};
+use crate::tokenizer::{self, split_top_level, Part, Scanner};
+use cssparser::{BasicParseErrorKind, ParseErrorKind, SourceLocation};
+use ego_tree::{NodeId, NodeRef};
+use html5ever::Attribute;
+use scraper::selector::ToCss;
+use scraper::{ElementRef, Node, Selector};
+use selectors::parser::{ParseRelative, SelectorList, SelectorParseErrorKind};
+use std::collections::HashSet;
+use std::ops::Range;
+use std::rc::Rc;
+
+/// How a compound relates to the compound on its left.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Combinator {
+    /// `a b`
+    Descendant,
+    /// `a > b`
+    Child,
+    /// `a + b`
+    NextSibling,
+    /// `a ~ b`
+    SubsequentSibling,
+}
+
+impl Combinator {
+    fn symbol(self) -> &'static str {
+        match self {
+            Combinator::Descendant => " ",
+            Combinator::Child => ">",
+            Combinator::NextSibling => "+",
+            Combinator::SubsequentSibling => "~",
+        }
+    }
+
+    /// The elements this combinator relates to any of `previous` (in document order)
+    /// on the right-hand side.
+    fn related(self, previous: &[ElementRef]) -> HashSet<NodeId> {
+        let mut found = HashSet::new();
+        for el in previous {
+            match self {
+                Combinator::Descendant => {
+                    // Already inside an earlier element's subtree, which covers this one's.
+                    if found.contains(&el.id()) {
+                        continue;
+                    }
+                    found.extend(
+                        el.descendants()
+                            .skip(1)
+                            .filter(|n| n.value().is_element())
+                            .map(|n| n.id()),
+                    );
+                }
+                Combinator::Child => found.extend(
+                    el.children()
+                        .filter(|n| n.value().is_element())
+                        .map(|n| n.id()),
+                ),
+                Combinator::NextSibling => found.extend(
+                    el.next_siblings()
+                        .find_map(ElementRef::wrap)
+                        .map(|s| s.id()),
+                ),
+                Combinator::SubsequentSibling => {
+                    for sibling in el.next_siblings().filter_map(ElementRef::wrap) {
+                        // An earlier sibling already took the rest.
+                        if !found.insert(sibling.id()) {
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        found
+    }
+}
+
+/// Where a cursor stopped: the last element it matched, and the node that followed
+/// that element's subtree, to resume from if the element is removed meanwhile.
+#[derive(Clone, Copy)]
+pub(crate) struct Resume {
+    last: NodeId,
+    past_last: Option<NodeId>,
+}
+
+/// State shared by every filter evaluated during one select.
+#[derive(Clone)]
+pub(crate) struct MatchContext<'a> {
+    pub doc: &'a DocEntry,
+    /// The element the select was run on, if any.
+    pub root: Option<NodeId>,
+    /// Element texts already collected by earlier filters in this select.
+    pub texts: Rc<TextCache>,
+    /// How the :contains family compares text.
+    pub normalization: Normalization,
+}
+
+impl<'a> MatchContext<'a> {
+    fn new(sel: &JsoupSelector, doc: &'a DocEntry, root: Option<NodeId>) -> Self {
+        MatchContext {
+            doc,
+            root,
+            texts: Rc::default(),
+            normalization: sel.normalization.unwrap_or(doc.normalization),
+        }
+    }
+}
+
+/// A Jsoup-only pseudo-selector attached to a compound.
+enum Filter {
+    Contains(ContainsFilter),
+    Index(IndexFilter),
+    Attr(AttrFilter),
+    /// `:has(sel)` whose argument needs Jsoup evaluation.
+    Has(Box<JsoupSelector>),
+    /// `:not(sel)` whose argument needs Jsoup evaluation.
+    Not(Box<JsoupSelector>),
+    /// `:matchText` — only the synthetic elements created by [`JsoupSelector::prepare`].
+    MatchText,
+    /// `:scope`, and the implied left side of a leading combinator — the element the
+    /// select was run on.
+    Scope,
+    /// A pseudo-class from the registry.
+    Registered(Box<dyn PseudoClass>),
+}
+
+impl Filter {
+    fn matches(&self, el: &ElementRef, ctx: &MatchContext) -> bool {
+        match self {
+            Filter::Contains(f) => {
+                contains_filter::matches_filter(f, el, &ctx.texts, ctx.normalization)
+            }
+            Filter::Index(f) => index_filter::matches_index(f, el, ctx.root),
+            Filter::Attr(f) => attr_filter::matches_attr(f, el),
+            // Jsoup evaluates the inner selector with the candidate as its root.
+            Filter::Has(sel) => {
+                let inner = MatchContext {
+                    root: Some(el.id()),
+                    ..ctx.clone()
+                };
+                el.descendants()
+                    .skip(1)
+                    .filter_map(ElementRef::wrap)
+                    .any(|d| sel.matches(&d, &inner))
+            }
+            Filter::Not(sel) => !sel.matches(el, ctx),
+            Filter::MatchText => ctx.doc.pseudo_text.contains(&el.id()),
+            Filter::Scope => ctx.root == Some(el.id()),
+            Filter::Registered(pseudo) => pseudo.matches(el, ctx),
+        }
+    }
+
+    /// Rough evaluation cost, so a compound runs its cheap filters first and the
+    /// expensive ones only on elements that got past them.
+    fn cost(&self) -> u8 {
+        match self {
+            Filter::MatchText | Filter::Scope | Filter::Index(_) => 0,
+            Filter::Attr(_) => 1,
+            Filter::Contains(f) if !f.kind.reads_subtree() => 2,
+            Filter::Contains(_) => 3,
+            Filter::Not(_) => 4,
+            Filter::Has(_) => 5,
+            Filter::Registered(pseudo) => pseudo.cost(),
+        }
+    }
+}
+
+/// One compound selector (e.g. `div.info:contains(Status)`): the part scraper can
+/// parse, plus the Jsoup pseudo filters that were attached to it.
+struct Compound {
+    selector: Selector,
+    filters: Vec<Filter>,
+    /// How each of `filters` was written.
+    filter_sources: Vec<String>,
+    /// The compound as written.
+    source: String,
+    /// Relation to the previous compound in the chain. Ignored for the first compound.
+    combinator: Combinator,
+    /// What an element needs to match `selector`, for looking it up in the document index.
+    keys: Option<Vec<IndexKey>>,
+}
+
+impl Compound {
+    fn matches(&self, el: &ElementRef, ctx: &MatchContext) -> bool {
+        self.selector.matches(el) && self.filters.iter().all(|f| f.matches(el, ctx))
+    }
+}
+
+/// A complex selector: compounds joined by combinators, leftmost first.
+struct Chain {
+    compounds: Vec<Compound>,
+    /// The complex selector as written.
+    source: String,
+}
+
+impl Chain {
+    fn matches(&self, el: &ElementRef, ctx: &MatchContext) -> bool {
+        self.matches_at(self.compounds.len() - 1, el, ctx)
+    }
+
+    /// The index keys of the leftmost compound, if every match lies within an element
+    /// matching it.
+    fn index_keys(&self) -> Option<Vec<IndexKey>> {
+        if self.compounds.get(1).is_some_and(|c| {
+            matches!(
+                c.combinator,
+                Combinator::NextSibling | Combinator::SubsequentSibling
+            )
+        }) {
+            return None;
+        }
+        self.compounds[0].keys.clone()
+    }
+
+    /// Match `el` against compound `idx`, then walk left through the combinators,
+    /// backtracking over every candidate ancestor/sibling.
+    fn matches_at(&self, idx: usize, el: &ElementRef, ctx: &MatchContext) -> bool {
+        let compound = &self.compounds[idx];
+        if !compound.matches(el, ctx) {
+            return false;
+        }
+        if idx == 0 {
+            return true;
+        }
+        match compound.combinator {
+            Combinator::Child => {
+                parent_element(el).is_some_and(|p| self.matches_at(idx - 1, &p, ctx))
+            }
+            Combinator::Descendant => {
+                let mut cur = parent_element(el);
+                while let Some(p) = cur {
+                    if self.matches_at(idx - 1, &p, ctx) {
+                        return true;
+                    }
+                    cur = parent_element(&p);
+                }
+                false
+            }
+            Combinator::NextSibling => {
+                prev_element_sibling(el).is_some_and(|s| self.matches_at(idx - 1, &s, ctx))
+            }
+            Combinator::SubsequentSibling => {
+                let mut cur = prev_element_sibling(el);
+                while let Some(s) = cur {
+                    if self.matches_at(idx - 1, &s, ctx) {
+                        return true;
+                    }
+                    cur = prev_element_sibling(&s);
+                }
+                false
+            }
+        }
+    }
+}
+
+/// The node after `node` in document order, without leaving the subtree of `bound`.
+fn following<'a>(node: NodeRef<'a, Node>, bound: NodeId) -> Option<NodeRef<'a, Node>> {
+    node.first_child().or_else(|| past_subtree(node, bound))
+}
+
+/// The first node after the subtree of `node` in document order, without leaving the
+/// subtree of `bound`.
+fn past_subtree<'a>(node: NodeRef<'a, Node>, bound: NodeId) -> Option<NodeRef<'a, Node>> {
+    let mut current = node;
+    loop {
+        if current.id() == bound {
+            return None;
+        }
+        if let Some(next) = current.next_sibling() {
+            return Some(next);
+        }
+        current = current.parent()?;
+    }
+}
+
+fn parent_element<'a>(el: &ElementRef<'a>) -> Option<ElementRef<'a>> {
+    el.parent().and_then(ElementRef::wrap)
+}
+
+fn prev_element_sibling<'a>(el: &ElementRef<'a>) -> Option<ElementRef<'a>> {
+    el.prev_siblings().find_map(ElementRef::wrap)
+}
+
+enum Inner {
+    /// Plain CSS — handed to scraper as a whole.
+    Css(Selector),
+    /// Comma-separated alternatives containing Jsoup pseudos, matched compound by compound.
+    Chains(Vec<Chain>),
+}
+
+/// A selector string with Jsoup-only pseudo-selectors evaluated on the compound
+/// they are attached to, at any position in a chain of combinators.
+pub(crate) struct JsoupSelector {
+    inner: Inner,
+    /// Set when the selector ends in `::text` or `::attr(name)`.
+    projection: Option<Projection>,
+    /// Index keys for each alternative, when all of them have some.
+    index_keys: Option<Vec<Vec<IndexKey>>>,
+    /// Overrides the document's text normalization.
+    normalization: Option<Normalization>,
+}
+
+impl JsoupSelector {
+    /// Parse a selector string, failing with the first invalid part of it.
+    pub(crate) fn parse(sel_str: &str) -> Result<Self, SelectorError<'_>> {
+        Self::parse_with(sel_str, true)
+    }
+
+    /// Parse a selector into its compounds even where scraper could take it whole, so
+    /// [`JsoupSelector::explain`] can report on each of them.
+    pub(crate) fn parse_compounds(sel_str: &str) -> Result<Self, SelectorError<'_>> {
+        Self::parse_with(sel_str, false)
+    }
+
+    fn parse_with(sel_str: &str, whole_css: bool) -> Result<Self, SelectorError<'_>> {
+        if sel_str.trim().is_empty() {
+            return Err(SelectorError::new("String must not be empty", sel_str));
+        }
+        let alternatives = split_top_level(sel_str, ',');
+        if let Some(&missing) = alternatives.iter().find(|alt| alt.trim().is_empty()) {
+            // `div,` or `a,,b`: point at the comma with nothing on one side.
+            let at = offset_in(sel_str, missing);
+            let comma = sel_str[..at]
+                .rfind(',')
+                .unwrap_or_else(|| at + missing.len());
+            return Err(unexpected(sel_str, &sel_str[comma..comma + 1]));
+        }
+        let (selectors, projection) = pseudo_element::split(&alternatives, SCOPE_QUERY)?;
+        if whole_css && projection.is_none() && !has_jsoup_pseudos(sel_str) && !is_relative(sel_str)
+        {
+            return parse_css(sel_str)
+                .map(|s| JsoupSelector {
+                    inner: Inner::Css(s),
+                    projection: None,
+                    index_keys: element_index::leftmost_keys(sel_str),
+                    normalization: None,
+                })
+                .map_err(|(detail, range)| {
+                    SelectorError::new(
+                        format!("Could not parse query '{sel_str}': {detail}"),
+                        &sel_str[range],
+                    )
+                });
+        }
+        let chains = selectors
+            .into_iter()
+            .map(parse_chain)
+            .collect::<Result<Vec<Chain>, _>>()?;
+        let index_keys = chains.iter().map(Chain::index_keys).collect();
+        Ok(JsoupSelector {
+            inner: Inner::Chains(chains),
+            projection,
+            index_keys,
+            normalization: None,
+        })
+    }
+
+    /// Compare text the given way, whatever the document is set to.
+    pub(crate) fn with_normalization(self, normalization: Normalization) -> Self {
+        JsoupSelector {
+            normalization: Some(normalization),
+            ..self
+        }
+    }
+
+    /// What to take from each matched element, if the selector ends in a pseudo-element.
+    pub(crate) fn projection(&self) -> Option<&Projection> {
+        self.projection.as_ref()
+    }
+
+    pub(crate) fn matches(&self, el: &ElementRef, ctx: &MatchContext) -> bool {
+        match &self.inner {
+            Inner::Css(sel) => sel.matches(el),
+            Inner::Chains(chains) => chains.iter().any(|c| c.matches(el, ctx)),
+        }
+    }
+
+    /// All matching elements in the document. When the leftmost compound of every
+    /// alternative has an id, class or tag, only the subtrees of the elements the
+    /// document index lists for it are searched.
+    pub(crate) fn select_doc<'a>(
+        &'a self,
+        doc: &'a DocEntry,
+    ) -> Box<dyn Iterator<Item = ElementRef<'a>> + 'a> {
+        let ctx = MatchContext::new(self, doc, None);
+        let candidates: Box<dyn Iterator<Item = NodeRef<'a, Node>>> = match &self.index_keys {
+            Some(keys) => Box::new(
+                doc.index()
+                    .anchors(keys)
+                    .into_iter()
+                    .filter_map(|id| doc.html.tree.get(id))
+                    .flat_map(|anchor| anchor.descendants()),
+            ),
+            None => Box::new(doc.html.tree.root().descendants()),
+        };
+        Box::new(
+            candidates
+                .filter_map(ElementRef::wrap)
+                .filter(move |el| self.matches(el, &ctx)),
+        )
+    }
+
+    /// Matching elements under `el`. Jsoup includes the element itself if it matches,
+    /// and never looks outside it: `+ p` from an element matches nothing.
+    pub(crate) fn select_within<'a>(
+        &'a self,
+        doc: &'a DocEntry,
+        el: ElementRef<'a>,
+    ) -> Box<dyn Iterator<Item = ElementRef<'a>> + 'a> {
+        let ctx = MatchContext::new(self, doc, Some(el.id()));
+        let self_ctx = ctx.clone();
+        let this = std::iter::once(el).filter(move |e| self.matches(e, &self_ctx));
+        match &self.inner {
+            Inner::Css(sel) => Box::new(this.chain(el.select(sel))),
+            Inner::Chains(_) => Box::new(
+                this.chain(
+                    el.descendants()
+                        .skip(1)
+                        .filter_map(ElementRef::wrap)
+                        .filter(move |e| self.matches(e, &ctx)),
+                ),
+            ),
+        }
+    }
+
+    /// The first match after `after` in document order, or the first match at all,
+    /// among what [`JsoupSelector::select_within`] (or [`JsoupSelector::select_doc`]
+    /// without a `root`) would search, with where to resume after it. Lets a cursor
+    /// carry on where it stopped, even if the document changed in between.
+    pub(crate) fn next_match<'a>(
+        &self,
+        doc: &'a DocEntry,
+        root: Option<NodeId>,
+        after: Option<Resume>,
+    ) -> Option<(ElementRef<'a>, Resume)> {
+        let start = match root {
+            Some(id) => doc.html.tree.get(id)?,
+            None => doc.html.tree.root(),
+        };
+        let bound = start.id();
+        let within =
+            |node: NodeRef<Node>| node.id() == bound || node.ancestors().any(|a| a.id() == bound);
+        let mut node = match after {
+            None => Some(start),
+            Some(resume) => match doc.html.tree.get(resume.last) {
+                Some(last) if within(last) => following(last, bound),
+                // The last match was removed: carry on from what followed it then,
+                // unless that went too.
+                _ => resume
+                    .past_last
+                    .and_then(|id| doc.html.tree.get(id))
+                    .filter(|&node| within(node)),
+            },
+        };
+        let ctx = MatchContext::new(self, doc, root);
+        while let Some(current) = node {
+            if let Some(el) = ElementRef::wrap(current).filter(|el| self.matches(el, &ctx)) {
+                let resume = Resume {
+                    last: el.id(),
+                    past_last: past_subtree(current, bound).map(|n| n.id()),
+                };
+                return Some((el, resume));
+            }
+            node = following(current, bound);
+        }
+        None
+    }
+
+    /// Run a selector parsed by [`JsoupSelector::parse_compounds`] one compound at a
+    /// time, counting what each step of each alternative matched. Steps count over the
+    /// whole document, as combinators may look above `root`; only the final matches
+    /// are limited to what a select under `root` would return.
+    pub(crate) fn explain(&self, doc: &DocEntry, root: Option<NodeId>) -> Vec<ChainReport> {
+        let Inner::Chains(chains) = &self.inner else {
+            return Vec::new();
+        };
+        let ctx = MatchContext::new(self, doc, root);
+        let elements: Vec<ElementRef> = doc
+            .html
+            .tree
+            .root()
+            .descendants()
+            .filter_map(ElementRef::wrap)
+            .collect();
+        let scope: Option<HashSet<NodeId>> = root.and_then(|id| {
+            let el = doc.html.tree.get(id)?;
+            Some(el.descendants().map(|n| n.id()).collect())
+        });
+        chains
+            .iter()
+            .map(|chain| {
+                let mut steps = Vec::new();
+                let mut previous: Option<Vec<ElementRef>> = None;
+                for compound in &chain.compounds {
+                    let mut survivors: Vec<ElementRef> = match &previous {
+                        None => elements.clone(),
+                        Some(previous) => {
+                            let related = compound.combinator.related(previous);
+                            elements
+                                .iter()
+                                .filter(|el| related.contains(&el.id()))
+                                .copied()
+                                .collect()
+                        }
+                    };
+                    let candidates = survivors.len();
+                    survivors.retain(|el| compound.selector.matches(el));
+                    let matched_css = survivors.len();
+                    let mut filters = Vec::new();
+                    for (filter, source) in compound.filters.iter().zip(&compound.filter_sources) {
+                        let before = survivors.len();
+                        survivors.retain(|el| filter.matches(el, &ctx));
+                        filters.push((source.clone(), before - survivors.len()));
+                    }
+                    steps.push(StepReport {
+                        compound: compound.source.clone(),
+                        combinator: previous.as_ref().map(|_| compound.combinator.symbol()),
+                        candidates,
+                        matched_css,
+                        filters,
+                        matches: survivors.len(),
+                    });
+                    previous = Some(survivors);
+                }
+                let matches = previous
+                    .unwrap_or_default()
+                    .iter()
+                    .map(|el| el.id())
+                    .filter(|id| scope.as_ref().is_none_or(|scope| scope.contains(id)))
+                    .collect();
+                ChainReport {
+                    selector: chain.source.clone(),
+                    steps,
+                    matches,
+                }
+            })
+            .collect()
+    }
+
+    /// Whether selecting requires [`JsoupSelector::prepare`] to run first.
+    pub(crate) fn needs_prepare(&self) -> bool {
+        match &self.inner {
+            Inner::Css(_) => false,
+            Inner::Chains(chains) => chains
+                .iter()
+                .flat_map(|c| &c.compounds)
+                .any(Compound::needs_prepare),
+        }
+    }
+
+    /// Apply the DOM changes `:matchText` relies on, like Jsoup does: every text
+    /// node child of an element matching the rest of its compound is wrapped in a
+    /// synthetic element carrying the parent's tag and attributes. `root` limits
+    /// the work to that element's subtree. [`unwrap_pseudo_text`] undoes it once the
+    /// select is done.
+    pub(crate) fn prepare(&self, doc: &mut DocEntry, root: Option<NodeId>) {
+        let Inner::Chains(chains) = &self.inner else {
+            return;
+        };
+        for compound in chains.iter().flat_map(|c| &c.compounds) {
+            compound.prepare(doc, root);
+        }
+    }
+}
+
+impl Compound {
+    fn needs_prepare(&self) -> bool {
+        self.filters.iter().any(|f| match f {
+            Filter::MatchText => true,
+            Filter::Has(sel) | Filter::Not(sel) => sel.needs_prepare(),
+            _ => false,
+        })
+    }
+
+    fn prepare(&self, doc: &mut DocEntry, root: Option<NodeId>) {
+        for f in &self.filters {
+            if let Filter::Has(sel) | Filter::Not(sel) = f {
+                sel.prepare(doc, root);
+            }
+        }
+        if !self.filters.iter().any(|f| matches!(f, Filter::MatchText)) {
+            return;
+        }
+        let scope = match root {
+            Some(id) => doc.html.tree.get(id),
+            None => Some(doc.html.tree.root()),
+        };
+        let Some(scope) = scope else {
+            return;
+        };
+        let parents: Vec<NodeId> = scope
+            .descendants()
+            .filter_map(ElementRef::wrap)
+            .filter(|el| !doc.pseudo_text.contains(&el.id()) && self.selector.matches(el))
+            .map(|el| el.id())
+            .collect();
+        let mut wrapped = false;
+        for parent_id in parents {
+            wrapped |= wrap_text_children(doc, parent_id);
+        }
+        if wrapped {
+            doc.tree_changed();
+        }
+    }
+}
+
+/// Wrap each text node child of `parent_id` in a copy of the parent element.
+/// Returns whether there were any.
+fn wrap_text_children(doc: &mut DocEntry, parent_id: NodeId) -> bool {
+    let Some(parent) = doc.html.tree.get(parent_id) else {
+        return false;
+    };
+    let Node::Element(parent_el) = parent.value() else {
+        return false;
+    };
+    let template = parent_el.clone();
+    let text_ids: Vec<NodeId> = parent
+        .children()
+        .filter(|c| c.value().is_text())
+        .map(|c| c.id())
+        .collect();
+    for &text_id in &text_ids {
+        let pseudo = scraper::node::Element::new(
+            template.name.clone(),
+            template
+                .attrs
+                .iter()
+                .map(|(name, value)| Attribute {
+                    name: name.clone(),
+                    value: (**value).into(),
+                })
+                .collect(),
+        );
+        let Some(mut text_mut) = doc.html.tree.get_mut(text_id) else {
+            continue;
+        };
+        let mut pseudo_mut = text_mut.insert_before(Node::Element(pseudo));
+        pseudo_mut.append_id(text_id);
+        doc.pseudo_text.insert(pseudo_mut.id());
+    }
+    !text_ids.is_empty()
+}
+
+/// Undo [`JsoupSelector::prepare`]: put every wrapped text node back where it was,
+/// so the document reads and selects as if nothing had been wrapped. The wrappers
+/// live on detached, each with a copy of its text, for the handles the select
+/// returned.
+pub(crate) fn unwrap_pseudo_text(doc: &mut DocEntry) {
+    if doc.pseudo_text.is_empty() {
+        return;
+    }
+    for pseudo_id in std::mem::take(&mut doc.pseudo_text) {
+        let Some(pseudo) = doc.html.tree.get(pseudo_id) else {
+            continue;
+        };
+        let children: Vec<(NodeId, Node)> = pseudo
+            .children()
+            .map(|c| (c.id(), c.value().clone()))
+            .collect();
+        let Some(mut pseudo_mut) = doc.html.tree.get_mut(pseudo_id) else {
+            continue;
+        };
+        for &(child_id, _) in &children {
+            pseudo_mut.insert_id_before(child_id);
+        }
+        pseudo_mut.detach();
+        for (_, value) in children {
+            pseudo_mut.append(value);
+        }
+    }
+    doc.tree_changed();
+}
+
+/// Whether the selector uses any pseudo-selector or attribute form that scraper
+/// cannot evaluate itself, at any nesting depth.
+fn has_jsoup_pseudos(sel_str: &str) -> bool {
+    let Ok(parts) = tokenizer::tokenize(sel_str) else {
+        return true;
+    };
+    parts.iter().any(|part| {
+        parse_filter(part).is_some()
+            || matches!(part, Part::Pseudo { arg: Some(arg), .. } if has_jsoup_pseudos(arg))
+    })
+}
+
+/// Parse plain CSS with scraper. On failure, returns a readable description of the
+/// problem and the byte range of the token it is about, which is never empty unless
+/// `css` is blank.
+fn parse_css(css: &str) -> Result<Selector, (String, Range<usize>)> {
+    if let Ok(selector) = Selector::parse(css) {
+        return Ok(selector);
+    }
+    // scraper drops the position, so re-run the same parse to get it back.
+    let mut input = cssparser::ParserInput::new(css);
+    let mut parser = cssparser::Parser::new(&mut input);
+    let range =
+        match SelectorList::parse(&scraper::selector::Parser, &mut parser, ParseRelative::No) {
+            Ok(_) => css.len()..css.len(),
+            Err(err) => error_range(css, &err.kind, byte_offset(css, err.location)),
+        };
+    let range = non_empty(css, range);
+    let token = &css[range.clone()];
+    let detail = match empty_sub_select(css, &range) {
+        Some(name) => format!(":{name}(selector) sub-select must not be empty"),
+        None => format!("unexpected token at '{token}'"),
+    };
+    Err((detail, range))
+}
+
+/// Byte range of the token a cssparser error at `at` is about, if it can tell.
+fn error_range(
+    css: &str,
+    kind: &ParseErrorKind<SelectorParseErrorKind>,
+    at: usize,
+) -> Range<usize> {
+    use SelectorParseErrorKind::*;
+    match kind {
+        // Reported either at the token or just past it, depending on the rule.
+        ParseErrorKind::Basic(BasicParseErrorKind::UnexpectedToken(token)) => {
+            let token = token.to_css_string();
+            if css[at..].starts_with(&token) {
+                at..at + token.len()
+            } else if css[..at].ends_with(&token) {
+                at - token.len()..at
+            } else {
+                at..at
+            }
+        }
+        ParseErrorKind::Custom(DanglingCombinator) => combinator_range(css, at),
+        // A pseudo-element where none is allowed (`div:has(p::text)`) is reported at
+        // the end of the argument.
+        ParseErrorKind::Custom(
+            InvalidState
+            | NonCompoundSelector
+            | NonPseudoElementAfterSlotted
+            | InvalidPseudoElementAfterSlotted
+            | InvalidPseudoElementInsideWhere,
+        ) => css[..at].rfind("::").map_or(at..at, |start| start..at),
+        ParseErrorKind::Custom(ClassNeedsIdent(_)) if css[..at].ends_with('.') => at - 1..at,
+        ParseErrorKind::Custom(custom) => custom_token_range(css, at, custom),
+        _ => at..at,
+    }
+}
+
+/// Widen an empty `range` to the next non-blank character, or else the last one
+/// before it.
+fn non_empty(css: &str, range: Range<usize>) -> Range<usize> {
+    if !range.is_empty() {
+        return range;
+    }
+    let at = range.start;
+    let next = css[at..]
+        .char_indices()
+        .find(|(_, c)| !c.is_whitespace())
+        .map(|(i, c)| at + i..at + i + c.len_utf8());
+    let previous = || {
+        css[..at]
+            .char_indices()
+            .rfind(|(_, c)| !c.is_whitespace())
+            .map(|(i, c)| i..i + c.len_utf8())
+    };
+    next.or_else(previous).unwrap_or(range)
+}
+
+/// The name of the functional pseudo-class whose argument is empty, if `range` is its
+/// closing parenthesis (`div:not()`).
+fn empty_sub_select<'a>(css: &'a str, range: &Range<usize>) -> Option<&'a str> {
+    if &css[range.clone()] != ")" {
+        return None;
+    }
+    let before = css[..range.start].trim_end().strip_suffix('(')?;
+    let colon = before.rfind(':')?;
+    let name = &before[colon + 1..];
+    (!name.is_empty() && name.chars().all(|c| c.is_alphanumeric() || c == '-')).then_some(name)
+}
+
+/// Byte range of the combinator a dangling-combinator error at `at` is about: the
+/// one that follows (`div > > span`), or else the last one before (`p >`).
+fn combinator_range(css: &str, at: usize) -> Range<usize> {
+    let is_combinator = |c: char| matches!(c, '>' | '+' | '~');
+    let rest = &css[at..];
+    let found = match rest.trim_start().chars().next() {
+        Some(c) if is_combinator(c) => Some(at + rest.len() - rest.trim_start().len()),
+        _ => css[..at].rfind(is_combinator),
+    };
+    found.map_or(at..at, |start| start..start + 1)
+}
+
+/// Byte range of the token a custom selectors error at `at` is about.
+fn custom_token_range(css: &str, at: usize, kind: &SelectorParseErrorKind) -> Range<usize> {
+    use SelectorParseErrorKind::*;
+    match kind {
+        NoQualifiedNameInAttributeSelector(token)
+        | UnexpectedTokenInAttributeSelector(token)
+        | PseudoElementExpectedColon(token)
+        | PseudoElementExpectedIdent(token)
+        | NoIdentForPseudo(token)
+        | ExpectedBarInAttr(token)
+        | BadValueInAttr(token)
+        | InvalidQualNameInAttr(token)
+        | ExplicitNamespaceUnexpectedToken(token)
+        | ClassNeedsIdent(token) => {
+            let mut end = (at + token.to_css_string().len()).min(css.len());
+            while !css.is_char_boundary(end) {
+                end -= 1;
+            }
+            at..end
+        }
+        // A functional pseudo is reported after its `(`, and a pseudo-element at its
+        // `::`, so take the occurrence of the name nearest `at`, with its colons.
+        UnsupportedPseudoClassOrElement(name) | UnexpectedIdent(name) | ExpectedNamespace(name) => {
+            let Some(start) = css
+                .match_indices(name.as_ref())
+                .map(|(i, _)| i)
+                .min_by_key(|&i| i.abs_diff(at))
+            else {
+                return at..at;
+            };
+            let colons = css[..start].len() - css[..start].trim_end_matches(':').len();
+            start - colons..start + name.len()
+        }
+        _ => at..at,
+    }
+}
+
+/// Convert a cssparser location (0-based line, 1-based UTF-16 column) to a byte offset.
+fn byte_offset(s: &str, location: SourceLocation) -> usize {
+    let line_start: usize = s
+        .split_inclusive('\n')
+        .take(location.line as usize)
+        .map(str::len)
+        .sum();
+    let mut column = 1;
+    for (i, c) in s[line_start..].char_indices() {
+        if column >= location.column as usize {
+            return line_start + i;
+        }
+        column += c.len_utf16();
+    }
+    s.len()
+}
+
+/// Jsoup's error for a selector that stops making sense at `token`.
+fn unexpected<'a>(query: &str, token: &'a str) -> SelectorError<'a> {
+    SelectorError::new(
+        format!(
+            "Could not parse query '{}': unexpected token at '{token}'",
+            query.trim()
+        ),
+        token,
+    )
+}
+
+/// Parse one complex selector into its compounds.
+fn parse_chain(s: &str) -> Result<Chain, SelectorError<'_>> {
+    let mut compounds = Vec::new();
+    // The combinator waiting for its right-hand compound, and where it was written.
+    let mut pending: Option<(Combinator, usize)> = None;
+    let mut start: Option<usize> = None;
+    let mut scanner = Scanner::new(s);
+
+    for (i, c, depth) in scanner.by_ref() {
+        if depth == 0 {
+            let combinator = match c {
+                '>' => Some(Combinator::Child),
+                '+' => Some(Combinator::NextSibling),
+                '~' => Some(Combinator::SubsequentSibling),
+                _ => None,
+            };
+            if combinator.is_some() || c.is_whitespace() {
+                if let Some(st) = start.take() {
+                    flush(&mut compounds, s, st..i, &mut pending)?;
+                }
+                if let Some(combinator) = combinator {
+                    // Two explicit combinators in a row (`a > > b`) is an error.
+                    if pending.is_some_and(|(p, _)| p != Combinator::Descendant) {
+                        return Err(unexpected(s, &s[i..i + c.len_utf8()]));
+                    }
+                    pending = Some((combinator, i));
+                } else if pending.is_none() && !compounds.is_empty() {
+                    pending = Some((Combinator::Descendant, i));
+                }
+                continue;
+            }
+        }
+        if start.is_none() {
+            start = Some(i);
+        }
+    }
+    if !scanner.is_balanced() {
+        // Let the tokenizer find the group or string that was left open.
+        return Err(tokenizer::tokenize(s)
+            .err()
+            .unwrap_or_else(|| tokenizer::unbalanced(s)));
+    }
+    if let Some(st) = start {
+        flush(&mut compounds, s, st..s.len(), &mut pending)?;
+    }
+    if compounds.is_empty() {
+        return Err(SelectorError::new("String must not be empty", s));
+    }
+    // Dangling combinator (`a >`).
+    if let Some((_, at)) = pending.filter(|&(p, _)| p != Combinator::Descendant) {
+        return Err(unexpected(s, &s[at..at + 1]));
+    }
+    Ok(Chain {
+        compounds,
+        source: s.trim().to_string(),
+    })
+}
+
+fn flush<'a>(
+    compounds: &mut Vec<Compound>,
+    s: &'a str,
+    range: Range<usize>,
+    pending: &mut Option<(Combinator, usize)>,
+) -> Result<(), SelectorError<'a>> {
+    let combinator = pending
+        .take()
+        .map_or(Combinator::Descendant, |(combinator, _)| combinator);
+    // A leading combinator relates to the element the select was run on (`> li`).
+    if compounds.is_empty() && combinator != Combinator::Descendant {
+        compounds.push(parse_compound(SCOPE_QUERY, Combinator::Descendant)?);
+    }
+    compounds.push(parse_compound(&s[range], combinator)?);
+    Ok(())
+}
+
+// Relational/negation pseudos that scraper supports natively, but not with
+// Jsoup-only pseudos in their argument.
+const NESTING_PSEUDOS: &[&str] = &["has", "not"];
+
+/// Parse `part` if it is a `:has(...)`/`:not(...)` whose argument uses Jsoup-only
+/// pseudos. Plain CSS arguments are left in place for scraper to evaluate.
+fn parse_nested<'a>(part: &Part<'a>) -> Option<Result<Filter, SelectorError<'a>>> {
+    NESTING_PSEUDOS.iter().find_map(|&name| {
+        let arg = part.pseudo_arg(name).filter(|arg| has_jsoup_pseudos(arg))?;
+        Some(JsoupSelector::parse(arg).and_then(|inner| {
+            if inner.projection.is_some() {
+                return Err(SelectorError::new(
+                    format!("Pseudo-elements are not allowed in :{name}"),
+                    arg,
+                ));
+            }
+            Ok(match name {
+                "has" => Filter::Has(Box::new(inner)),
+                _ => Filter::Not(Box::new(inner)),
+            })
+        }))
+    })
+}
+
+const MATCH_TEXT: &str = "matchText";
+const SCOPE: &str = "scope";
+const SCOPE_QUERY: &str = ":scope";
+
+/// Whether any alternative starts with a combinator, relative to the select's scope.
+fn is_relative(sel_str: &str) -> bool {
+    split_top_level(sel_str, ',')
+        .iter()
+        .any(|alt| alt.trim_start().starts_with(['>', '+', '~']))
+}
+
+/// Parse `part` if it is one of the Jsoup-only pseudo-selectors or attribute forms.
+fn parse_filter<'a>(part: &Part<'a>) -> Option<Result<Filter, SelectorError<'a>>> {
+    if let Part::Pseudo {
+        name, arg: None, ..
+    } = part
+    {
+        if name.eq_ignore_ascii_case(MATCH_TEXT) {
+            return Some(Ok(Filter::MatchText));
+        }
+        if name.eq_ignore_ascii_case(SCOPE) {
+            return Some(Ok(Filter::Scope));
+        }
+    }
+    parse_nested(part)
+        .or_else(|| contains_filter::parse_contains(part).map(|r| r.map(Filter::Contains)))
+        .or_else(|| index_filter::parse_index(part).map(|r| r.map(Filter::Index)))
+        .or_else(|| attr_filter::parse_attr(part).map(|r| r.map(Filter::Attr)))
+        .or_else(|| pseudo_registry::parse_registered(part).map(|r| r.map(Filter::Registered)))
+}
+
+fn parse_compound(text: &str, combinator: Combinator) -> Result<Compound, SelectorError<'_>> {
+    let mut base = String::with_capacity(text.len());
+    // Where each piece of `base` starts, so scraper's errors can point back into `text`.
+    let mut pieces: Vec<(usize, &str)> = Vec::new();
+    let mut filters = Vec::new();
+    for part in tokenizer::tokenize(text)? {
+        match parse_filter(&part) {
+            Some(filter) => filters.push((filter?, part.raw().to_string())),
+            None => {
+                pieces.push((base.len(), part.raw()));
+                base.push_str(part.raw());
+            }
+        }
+    }
+    filters.sort_by_key(|(filter, _)| filter.cost());
+    let (filters, filter_sources) = filters.into_iter().unzip();
+    let css = if base.trim().is_empty() { "*" } else { &base };
+    let selector = parse_css(css).map_err(|(detail, range)| {
+        SelectorError::new(
+            format!("Could not parse query '{text}': {detail}"),
+            locate(&pieces, range, text),
+        )
+    })?;
+    // A compound has no top-level comma, so this is its only alternative.
+    let keys = element_index::leftmost_keys(css).and_then(|mut alternatives| alternatives.pop());
+    Ok(Compound {
+        selector,
+        filters,
+        filter_sources,
+        source: text.to_string(),
+        combinator,
+        keys,
+    })
+}
+
+/// Map a byte range of a compound's rebuilt base selector back to the compound text.
+fn locate<'a>(pieces: &[(usize, &'a str)], range: Range<usize>, text: &'a str) -> &'a str {
+    let Some(&(start, piece)) = pieces
+        .iter()
+        .rev()
+        .find(|&&(start, _)| start <= range.start)
+    else {
+        return &text[text.len()..];
+    };
+    let from = (range.start - start).min(piece.len());
+    let to = (range.end - start).clamp(from, piece.len());
+    &piece[from..to]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::explain::scraper_explain_select;
+    use crate::mutation::{
+        scraper_remove_class, scraper_remove_element, scraper_set_attr, scraper_set_html,
+    };
+    use crate::test_support::{ids, outer_html, parse, selector};
+    use crate::{scraper_cursor_next, scraper_handle_error, scraper_select_cursor};
+    use crate::{scraper_free, scraper_free_string, select_all, select_entries, select_first};
+
+    // Expected results are what Jsoup returns for the same document and query, except
+    // where noted.
+    const HTML: &str = r#"<div id="d1" class="c"><ul id="u1"><li id="l1"><span id="s1">Raw</span></li><li id="l2">b</li></ul><p id="p1">x</p></div><div id="d2" class="c"><p id="p2">Vol 1</p><p id="p3">Ch 2</p></div>"#;
+
+    #[test]
+    fn chains_and_compounds() {
+        let doc = parse(HTML);
+        assert_eq!(ids(doc, "div.c > p:contains(ch)"), ["p3"]);
+        assert_eq!(ids(doc, "div:contains(Vol) p:eq(1)"), ["p3"]);
+        assert_eq!(ids(doc, "ul li:eq(0) + li"), ["l2"]);
+        assert_eq!(ids(doc, "div:has(span) ~ div p"), ["p2", "p3"]);
+        assert_eq!(ids(doc, "p:contains(2), li:contains(raw)"), ["l1", "p3"]);
+        // Filters run cheapest first; the result must not depend on it.
+        assert_eq!(
+            ids(doc, "li:has(span):containsOwn(b):eq(1)"),
+            Vec::<String>::new()
+        );
+        assert_eq!(ids(doc, "li:has(span):contains(raw):eq(0)"), ["l1"]);
+        unsafe { scraper_free(doc) };
+    }
+
+    #[test]
+    fn leading_combinators() {
+        let doc = parse(HTML);
+        let ul = select_first(doc, &selector("#u1"));
+        assert_eq!(ids(ul, "> li"), ["l1", "l2"]);
+        assert_eq!(ids(ul, "> li > span"), ["s1"]);
+        // Only the context element's subtree is searched, so its siblings never match.
+        assert_eq!(ids(ul, "+ p"), Vec::<String>::new());
+        let p2 = select_first(doc, &selector("#p2"));
+        assert_eq!(ids(p2, "~ p"), Vec::<String>::new());
+        assert_eq!(ids(p2, "+ p:contains(ch)"), Vec::<String>::new());
+        let d2 = select_first(doc, &selector("#d2"));
+        assert_eq!(ids(d2, "> p + p"), ["p3"]);
+        assert_eq!(ids(d2, "> p ~ p:contains(ch)"), ["p3"]);
+        unsafe { scraper_free(doc) };
+    }
+
+    #[test]
+    fn nested_jsoup_pseudos() {
+        let doc = parse(HTML);
+        assert_eq!(ids(doc, "div:has(> p)"), ["d1", "d2"]);
+        assert_eq!(ids(doc, "li:has(> span)"), ["l1"]);
+        assert_eq!(ids(doc, "ul:has(> span)"), Vec::<String>::new());
+        assert_eq!(ids(doc, "div:has(span:contains(Raw))"), ["d1"]);
+        assert_eq!(
+            ids(doc, "div:has(> span:contains(Raw))"),
+            Vec::<String>::new()
+        );
+        assert_eq!(ids(doc, "p:not(:matchesOwn(^Vol))"), ["p1", "p3"]);
+        assert_eq!(ids(doc, "div:not(:has(p:contains(vol)))"), ["d1"]);
+        unsafe { scraper_free(doc) };
+    }
+
+    #[test]
+    fn select_on_node_lists() {
+        let doc = parse(HTML);
+        let divs = select_all(doc, &selector("div.c"));
+        assert_eq!(ids(divs, "p"), ["p1", "p2", "p3"]);
+        // Overlapping contexts give each match once; a context can match itself.
+        let nested = select_all(doc, &selector("div, ul"));
+        assert_eq!(ids(nested, "li"), ["l1", "l2"]);
+        assert_eq!(ids(nested, "ul"), ["u1"]);
+        let ps = select_all(doc, &selector("p"));
+        assert_eq!(ids(ps, "p:contains(2)"), ["p3"]);
+        // A freed list is reported as freed, not as some other kind of handle.
+        unsafe { scraper_free(ps) };
+        assert!(select_entries(ps, &selector("p"), false).is_none());
+        assert_eq!(unsafe { scraper_handle_error() }, 3);
+        unsafe { scraper_free(doc) };
+    }
+
+    #[test]
+    fn indexes_follow_mutations() {
+        let doc = parse(HTML);
+        assert_eq!(ids(doc, "#p1"), ["p1"]);
+        assert_eq!(ids(doc, ".c"), ["d1", "d2"]);
+        assert_eq!(ids(doc, "#d2 p"), ["p2", "p3"]);
+        let p1 = select_first(doc, &selector("#p1"));
+        unsafe { scraper_set_attr(p1, c"id".as_ptr(), c"moved".as_ptr()) };
+        assert_eq!(ids(doc, "#p1"), Vec::<String>::new());
+        assert_eq!(ids(doc, "#moved"), ["moved"]);
+        let d1 = select_first(doc, &selector("#d1"));
+        unsafe { scraper_remove_class(d1, c"c".as_ptr()) };
+        assert_eq!(ids(doc, ".c"), ["d2"]);
+        let d2 = select_first(doc, &selector("#d2"));
+        unsafe { scraper_set_html(d2, c"<span id=s2>n</span>".as_ptr()) };
+        assert_eq!(ids(doc, "span"), ["s1", "s2"]);
+        assert_eq!(ids(doc, "#d2 p"), Vec::<String>::new());
+        unsafe { scraper_free(doc) };
+    }
+
+    #[test]
+    fn match_text_leaves_the_document_alone() {
+        let doc = parse("<ul><li>a <b>x</b> c</li></ul>");
+        let ul = select_first(doc, &selector("ul"));
+        let before = outer_html(ul);
+        let found = select_entries(doc, &selector("li:matchText"), false).unwrap();
+        assert_eq!(found.len(), 2);
+        let first = select_first(doc, &selector("li:matchText"));
+        assert_eq!(outer_html(first), "<li>a </li>");
+        assert_eq!(outer_html(ul), before);
+        assert_eq!(
+            select_entries(doc, &selector("li"), false).unwrap().len(),
+            1
+        );
+        assert_eq!(ids(doc, "b:nth-child(1)"), [""]);
+
+        let report = unsafe { scraper_explain_select(doc, c"li:matchText".as_ptr()) };
+        unsafe { scraper_free_string(report) };
+        let cursor = unsafe { scraper_select_cursor(doc, c"li:matchText".as_ptr(), 1, -1) };
+        assert_eq!(
+            outer_html(unsafe { scraper_cursor_next(cursor) }),
+            "<li> c</li>"
+        );
+        assert_eq!(unsafe { scraper_cursor_next(cursor) }, -1);
+        assert_eq!(outer_html(ul), before);
+        unsafe { scraper_free(doc) };
+    }
+
+    #[test]
+    fn cursors_survive_removing_what_they_returned() {
+        let doc = parse(HTML);
+        let cursor = unsafe { scraper_select_cursor(doc, c"li, p".as_ptr(), 0, -1) };
+        let mut seen = Vec::new();
+        loop {
+            let next = unsafe { scraper_cursor_next(cursor) };
+            if next == -1 {
+                break;
+            }
+            seen.push(outer_html(next));
+            unsafe { scraper_remove_element(next) };
+        }
+        assert_eq!(seen.len(), 5);
+        assert!(seen[0].starts_with(r#"<li id="l1">"#));
+        assert_eq!(seen[4], r#"<p id="p3">Ch 2</p>"#);
+        assert_eq!(ids(doc, "li, p"), Vec::<String>::new());
+        unsafe { scraper_free(doc) };
+    }
+
+    #[test]
+    fn error_tokens() {
+        // The token and where it is in the query, as `scraper_selector_error_offset` gives it.
+        fn token(query: &str) -> (&str, usize) {
+            let error = JsoupSelector::parse(query).err().unwrap();
+            (
+                error.token,
+                crate::selector_error::offset_in(query, error.token),
+            )
+        }
+        assert_eq!(token("div > > span"), (">", 6));
+        assert_eq!(token("div:contains(a) > > span"), (">", 18));
+        assert_eq!(token("p >"), (">", 2));
+        assert_eq!(token("div!"), ("!", 3));
+        assert_eq!(token("div::before"), ("::before", 3));
+        assert_eq!(token("div:has(p::text)"), ("::text", 9));
+        assert_eq!(token("div:contains(a),"), (",", 15));
+    }
+
+    #[test]
+    fn error_messages() {
+        fn message(query: &str) -> String {
+            JsoupSelector::parse(query).err().unwrap().message
+        }
+        assert_eq!(message(" "), "String must not be empty");
+        assert_eq!(
+            message("div:has(p::text)"),
+            "Could not parse query 'div:has(p::text)': unexpected token at '::text'"
+        );
+        assert_eq!(
+            message("div,"),
+            "Could not parse query 'div,': unexpected token at ','"
+        );
+        assert_eq!(
+            message("div:not()"),
+            "Could not parse query 'div:not()': :not(selector) sub-select must not be empty"
+        );
+        assert_eq!(
+            message("div:foo"),
+            "Could not parse query 'div:foo': unexpected token at ':foo'"
+        );
+    }
+}
@@ -1,18 +1,46 @@
+#![allow(clippy::missing_safety_doc)]
+
+mod attr_filter;
 mod contains_filter;
+mod element_index;
+mod explain;
+mod handle_report;
 mod handle_store;
+mod index_filter;
+mod java_regex;
+mod jsoup_selector;
 mod mutation;
+mod normalization;
+mod pseudo_element;
+mod pseudo_registry;
+mod selector_error;
+#[cfg(test)]
+mod test_support;
+mod tokenizer;
 mod url_resolver;
+mod xpath;
+mod xpath_parser;
 
-use ego_tree::NodeRef;
+use ego_tree::{NodeId, NodeRef};
+use element_index::sort_in_document_order;
 use handle_store::{
-    free_handle, get_node, get_node_list, is_document, release_all, store_document, store_node,
-    store_node_list, with_doc, with_node_doc, NodeEntry,
+    documents_of, free_handle, get_node, get_node_list, get_selector, handle_status, is_document,
+    is_node_list, release_all, store_cursor, store_document, store_node, store_node_list,
+    store_selector, with_cursor_mut, with_doc, with_doc_mut, with_node_doc, Cursor, DocEntry,
+    NodeEntry,
 };
 use html5ever::tree_builder::QuirksMode;
+use jsoup_selector::{unwrap_pseudo_text, JsoupSelector};
 use markup5ever::{ns, LocalName, QualName};
-use scraper::{Html, Node, Selector};
+use normalization::Normalization;
+use pseudo_element::PseudoElement;
+use scraper::node::Element;
+use scraper::{Html, Node};
+use std::collections::HashSet;
 use std::ffi::{c_char, c_int, CStr, CString};
 use std::ptr;
+use std::sync::Arc;
+use xpath::{XNode, XPath};
 
 // ---------------------------------------------------------------------------
 // Helpers
@@ -42,12 +70,33 @@ fn html_escape(s: &str) -> String {
     out
 }
 
+/// Whether attribute `name` of `el` is the one `key` names, compared the way Jsoup
+/// does: ignoring ASCII case on HTML elements. Namespaced attributes (`xlink:href`)
+/// never match a plain key.
+fn attr_key_matches(el: &Element, name: &QualName, key: &str) -> bool {
+    name.ns == ns!()
+        && if el.name.ns == ns!(html) {
+            str::eq_ignore_ascii_case(&name.local, key)
+        } else {
+            &*name.local == key
+        }
+}
+
+/// The value of the attribute `key` names on `el`, see [`attr_key_matches`].
+fn attr_value<'a>(el: &'a Element, key: &str) -> Option<&'a str> {
+    el.attrs
+        .iter()
+        .find(|(name, _)| attr_key_matches(el, name, key))
+        .map(|(_, value)| value.as_ref())
+}
+
 fn node_to_entry(node_ref: &NodeRef<Node>, doc_handle: i64) -> NodeEntry {
     let is_text = matches!(node_ref.value(), Node::Text(_));
     NodeEntry {
         node_id: node_ref.id(),
         doc_handle,
         is_text,
+        attr: None,
     }
 }
 
@@ -77,6 +126,17 @@ pub unsafe extern "C" fn scraper_free_handle_array(arr: *mut i64, _len: c_int) {
     }
 }
 
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn scraper_free_string_array(arr: *mut *mut c_char, len: c_int) {
+    if !arr.is_null() {
+        let len = len as usize;
+        let strings = unsafe { Vec::from_raw_parts(arr, len, len) };
+        for s in strings {
+            unsafe { scraper_free_string(s) };
+        }
+    }
+}
+
 // ---------------------------------------------------------------------------
 // Parsing
 // ---------------------------------------------------------------------------
@@ -113,92 +173,249 @@ pub unsafe extern "C" fn scraper_parse_fragment(
 // CSS Selectors
 // ---------------------------------------------------------------------------
 
-#[unsafe(no_mangle)]
-pub unsafe extern "C" fn scraper_select(
-    handle: i64,
-    selector: *const c_char,
-) -> i64 {
+/// Parse a selector argument, recording the outcome for `scraper_selector_error_*`.
+unsafe fn parse_selector(selector: *const c_char) -> Option<JsoupSelector> {
     let sel_str = match unsafe { cstr_to_str(selector) } {
         Some(s) => s,
-        None => return -1,
-    };
-    let (base_sel, filters) = contains_filter::strip_contains(sel_str);
-    let base_sel_str = if base_sel.trim().is_empty() { "*" } else { &base_sel };
-    let sel = match Selector::parse(base_sel_str) {
-        Ok(s) => s,
-        Err(_) => return -1,
+        None => {
+            selector_error::record_unreadable();
+            return None;
+        }
     };
+    selector_error::record(sel_str, JsoupSelector::parse(sel_str))
+}
 
+/// Select what `sel` matches under a document, element or node list handle: the
+/// matching elements, or what its pseudo-element takes from them. With `first_only`,
+/// stops once the first result is known. `None` for an invalid handle.
+fn select_entries(handle: i64, sel: &JsoupSelector, first_only: bool) -> Option<Vec<NodeEntry>> {
+    // A pseudo-element can come up empty on the first match, so look at them all.
+    let first_only = first_only && sel.projection().is_none();
+    if is_node_list(handle) {
+        return get_node_list(handle).map(|roots| select_in_list(&roots, sel, first_only));
+    }
     // handle could be a document or a node (element)
     if is_document(handle) {
-        with_doc(handle, |doc| {
-            let entries: Vec<NodeEntry> = doc
-                .html
-                .select(&sel)
-                .filter(|el| {
-                    filters.iter().all(|f| {
-                        let node_ref = doc.html.tree.get(el.id()).unwrap();
-                        contains_filter::matches_filter(f, el, &node_ref)
-                    })
-                })
-                .map(|el| NodeEntry {
-                    node_id: el.id(),
-                    doc_handle: handle,
-                    is_text: false,
-                })
-                .collect();
-            store_node_list(entries)
+        with_prepared(handle, sel, &[None], |doc| {
+            let matches = sel.select_doc(doc).map(|el| el.id());
+            let ids: Vec<NodeId> = if first_only {
+                matches.take(1).collect()
+            } else {
+                matches.collect()
+            };
+            project(doc, handle, ids, sel, &mut HashSet::new())
         })
-        .unwrap_or(-1)
     } else {
         // Node handle — need to select within this element
-        let entry = match get_node(handle) {
-            Some(e) => e,
-            None => return -1,
-        };
-        with_doc(entry.doc_handle, |doc| {
-            let node_ref = match doc.html.tree.get(entry.node_id) {
-                Some(n) => n,
-                None => return -1,
-            };
+        let entry = get_node(handle)?;
+        with_prepared(entry.doc_handle, sel, &[Some(entry.node_id)], |doc| {
+            let node_ref = doc.html.tree.get(entry.node_id)?;
             // Check if it's an element
-            if let Node::Element(_) = node_ref.value() {
-                let el_ref = scraper::ElementRef::wrap(node_ref).unwrap();
-                let mut entries: Vec<NodeEntry> = Vec::new();
-                // Jsoup includes the element itself if it matches the selector.
-                if sel.matches(&el_ref)
-                    && filters.iter().all(|f| {
-                        let nr = doc.html.tree.get(el_ref.id()).unwrap();
-                        contains_filter::matches_filter(f, &el_ref, &nr)
-                    })
+            let ids: Vec<NodeId> = match scraper::ElementRef::wrap(node_ref) {
+                Some(el_ref) => {
+                    let matches = sel.select_within(doc, el_ref).map(|el| el.id());
+                    if first_only {
+                        matches.take(1).collect()
+                    } else {
+                        matches.collect()
+                    }
+                }
+                None => Vec::new(),
+            };
+            Some(project(doc, entry.doc_handle, ids, sel, &mut HashSet::new()))
+        })
+        .flatten()
+    }
+}
+
+/// Run `f` on a document with the DOM changes `sel` relies on applied under each of
+/// `roots`, and undone again before anything else can see the document.
+fn with_prepared<R>(
+    doc_handle: i64,
+    sel: &JsoupSelector,
+    roots: &[Option<NodeId>],
+    f: impl FnOnce(&DocEntry) -> R,
+) -> Option<R> {
+    if !sel.needs_prepare() {
+        return with_doc(doc_handle, f);
+    }
+    with_doc_mut(doc_handle, |doc| {
+        for &root in roots {
+            sel.prepare(doc, root);
+        }
+        let result = f(doc);
+        unwrap_pseudo_text(doc);
+        result
+    })
+}
+
+/// Select everything `sel` matches under a document, element or node list handle.
+fn select_all(handle: i64, sel: &JsoupSelector) -> i64 {
+    let sources = source_docs(handle);
+    select_entries(handle, sel, false).map_or(-1, |entries| store_node_list(entries, sources))
+}
+
+/// The documents a select under a document, element or node list handle reads.
+fn source_docs(handle: i64) -> Vec<i64> {
+    if is_document(handle) {
+        vec![handle]
+    } else if is_node_list(handle) {
+        documents_of(handle)
+    } else {
+        get_node(handle)
+            .map(|entry| entry.doc_handle)
+            .into_iter()
+            .collect()
+    }
+}
+
+/// Select the first thing `sel` matches under a document, element or node list handle.
+fn select_first(handle: i64, sel: &JsoupSelector) -> i64 {
+    select_entries(handle, sel, true)
+        .and_then(|entries| entries.into_iter().next())
+        .map_or(-1, store_node)
+}
+
+/// Turn the elements a selector matched into node entries, applying its trailing
+/// `::text`/`::attr(name)` if it has one. Elements in `seen` were already taken from.
+fn project(
+    doc: &DocEntry,
+    doc_handle: i64,
+    ids: Vec<NodeId>,
+    sel: &JsoupSelector,
+    seen: &mut HashSet<NodeId>,
+) -> Vec<NodeEntry> {
+    let Some(projection) = sel.projection() else {
+        return ids
+            .into_iter()
+            .map(|node_id| NodeEntry {
+                node_id,
+                doc_handle,
+                is_text: false,
+                attr: None,
+            })
+            .collect();
+    };
+    let mut entries = Vec::new();
+    // With `descendants`, nested matches share elements; each is taken once.
+    for id in ids {
+        let Some(node_ref) = doc.html.tree.get(id) else {
+            continue;
+        };
+        // Spaced `::text` reads text in document order, not grouped by element.
+        if projection.descendants && projection.pseudo == PseudoElement::Text {
+            let mut fresh = HashSet::new();
+            for node in node_ref.descendants() {
+                if node.value().is_element() {
+                    if seen.insert(node.id()) {
+                        fresh.insert(node.id());
+                    }
+                } else if node.value().is_text()
+                    && node
+                        .parent()
+                        .is_some_and(|parent| fresh.contains(&parent.id()))
                 {
-                    entries.push(NodeEntry {
-                        node_id: el_ref.id(),
-                        doc_handle: entry.doc_handle,
-                        is_text: false,
-                    });
+                    entries.push(node_to_entry(&node, doc_handle));
                 }
-                entries.extend(
-                    el_ref
-                        .select(&sel)
-                        .filter(|el| {
-                            filters.iter().all(|f| {
-                                let nr = doc.html.tree.get(el.id()).unwrap();
-                                contains_filter::matches_filter(f, el, &nr)
-                            })
-                        })
-                        .map(|el| NodeEntry {
-                            node_id: el.id(),
-                            doc_handle: entry.doc_handle,
+            }
+            continue;
+        }
+        let sources: Vec<NodeRef<Node>> = if projection.descendants {
+            node_ref
+                .descendants()
+                .filter(|n| n.value().is_element())
+                .collect()
+        } else {
+            vec![node_ref]
+        };
+        for source in sources {
+            if !seen.insert(source.id()) {
+                continue;
+            }
+            match &projection.pseudo {
+                PseudoElement::Text => {
+                    entries.extend(
+                        source
+                            .children()
+                            .filter(|child| child.value().is_text())
+                            .map(|child| node_to_entry(&child, doc_handle)),
+                    );
+                }
+                PseudoElement::Attr(key) => {
+                    let Some(el) = source.value().as_element() else {
+                        continue;
+                    };
+                    if let Some((name, _)) = el
+                        .attrs
+                        .iter()
+                        .find(|(name, _)| attr_key_matches(el, name, key))
+                    {
+                        entries.push(NodeEntry {
+                            node_id: source.id(),
+                            doc_handle,
                             is_text: false,
-                        }),
-                );
-                store_node_list(entries)
-            } else {
-                store_node_list(Vec::new())
+                            attr: Some(name.clone()),
+                        });
+                    }
+                }
             }
-        })
-        .unwrap_or(-1)
+        }
+    }
+    entries
+}
+
+/// Select within every element of a node list, like Jsoup's `Elements.select`: each
+/// element is a context that can match itself, and matches reached from more than one
+/// context are kept once. Results are in document order, documents in list order.
+/// With `first_only`, each context contributes only its first match.
+fn select_in_list(roots: &[NodeEntry], sel: &JsoupSelector, first_only: bool) -> Vec<NodeEntry> {
+    let mut doc_handles: Vec<i64> = Vec::new();
+    for root in roots {
+        if !doc_handles.contains(&root.doc_handle) {
+            doc_handles.push(root.doc_handle);
+        }
+    }
+    let mut found = Vec::new();
+    for doc_handle in doc_handles {
+        let doc_roots: Vec<NodeId> = roots
+            .iter()
+            .filter(|r| r.doc_handle == doc_handle && !r.is_text && r.attr.is_none())
+            .map(|r| r.node_id)
+            .collect();
+        let prepare_roots: Vec<Option<NodeId>> = doc_roots.iter().copied().map(Some).collect();
+        with_prepared(doc_handle, sel, &prepare_roots, |doc| {
+            let mut seen = HashSet::new();
+            let mut ids: Vec<NodeId> = Vec::new();
+            for &root in &doc_roots {
+                let Some(el_ref) = doc.html.tree.get(root).and_then(scraper::ElementRef::wrap)
+                else {
+                    continue;
+                };
+                let matches = sel.select_within(doc, el_ref).map(|el| el.id());
+                let matches: Box<dyn Iterator<Item = NodeId>> = if first_only {
+                    Box::new(matches.take(1))
+                } else {
+                    Box::new(matches)
+                };
+                ids.extend(matches.filter(|id| seen.insert(*id)));
+            }
+            if doc_roots.len() > 1 {
+                sort_in_document_order(&doc.html, &mut ids);
+            }
+            found.extend(project(doc, doc_handle, ids, sel, &mut HashSet::new()));
+        });
+    }
+    found
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn scraper_select(
+    handle: i64,
+    selector: *const c_char,
+) -> i64 {
+    match unsafe { parse_selector(selector) } {
+        Some(sel) => select_all(handle, &sel),
+        None => -1,
     }
 }
 
@@ -207,82 +424,404 @@ pub unsafe extern "C" fn scraper_select_first(
     handle: i64,
     selector: *const c_char,
 ) -> i64 {
-    let sel_str = match unsafe { cstr_to_str(selector) } {
-        Some(s) => s,
-        None => return -1,
-    };
-    let (base_sel, filters) = contains_filter::strip_contains(sel_str);
-    let base_sel_str = if base_sel.trim().is_empty() { "*" } else { &base_sel };
-    let sel = match Selector::parse(base_sel_str) {
-        Ok(s) => s,
-        Err(_) => return -1,
+    match unsafe { parse_selector(selector) } {
+        Some(sel) => select_first(handle, &sel),
+        None => -1,
+    }
+}
+
+/// Parse a selector once for repeated use with `scraper_select_compiled`.
+/// Returns a handle freed with `scraper_free`, or -1 if the selector is invalid.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn scraper_compile_selector(selector: *const c_char) -> i64 {
+    match unsafe { parse_selector(selector) } {
+        Some(sel) => store_selector(sel),
+        None => -1,
+    }
+}
+
+/// Like `scraper_compile_selector`, but the :contains family compares text under
+/// `mode` on every document: 0 as Jsoup does, 1 after NFKC and case folding, 2 also
+/// folding katakana to hiragana. Returns -1 if the selector or mode is invalid.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn scraper_compile_selector_normalized(
+    selector: *const c_char,
+    mode: c_int,
+) -> i64 {
+    let Some(normalization) = Normalization::from_raw(mode) else {
+        return -1;
     };
+    match unsafe { parse_selector(selector) } {
+        Some(sel) => store_selector(sel.with_normalization(normalization)),
+        None => -1,
+    }
+}
 
-    if is_document(handle) {
-        with_doc(handle, |doc| {
-            doc.html
-                .select(&sel)
-                .find(|el| {
-                    filters.iter().all(|f| {
-                        let node_ref = doc.html.tree.get(el.id()).unwrap();
-                        contains_filter::matches_filter(f, el, &node_ref)
-                    })
-                })
-                .map_or(-1, |el| {
-                    store_node(NodeEntry {
-                        node_id: el.id(),
-                        doc_handle: handle,
-                        is_text: false,
-                    })
-                })
-        })
-        .unwrap_or(-1)
+/// Set how selects compare text in the :contains family on a document, or on the
+/// document a node belongs to. Modes are as for `scraper_compile_selector_normalized`.
+/// Returns 0, or -1 for an invalid handle or mode.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn scraper_set_text_normalization(handle: i64, mode: c_int) -> c_int {
+    let Some(normalization) = Normalization::from_raw(mode) else {
+        return -1;
+    };
+    let doc_handle = if is_document(handle) {
+        handle
     } else {
-        let entry = match get_node(handle) {
-            Some(e) => e,
+        match get_node(handle) {
+            Some(entry) => entry.doc_handle,
             None => return -1,
-        };
-        with_doc(entry.doc_handle, |doc| {
-            let node_ref = doc.html.tree.get(entry.node_id)?;
-            if let Node::Element(_) = node_ref.value() {
-                let el_ref = scraper::ElementRef::wrap(node_ref)?;
-                // Jsoup includes the element itself if it matches the selector.
-                if sel.matches(&el_ref)
-                    && filters.iter().all(|f| {
-                        let nr = doc.html.tree.get(el_ref.id()).unwrap();
-                        contains_filter::matches_filter(f, &el_ref, &nr)
-                    })
-                {
-                    return Some(store_node(NodeEntry {
-                        node_id: el_ref.id(),
-                        doc_handle: entry.doc_handle,
-                        is_text: false,
-                    }));
-                }
-                el_ref
-                    .select(&sel)
-                    .find(|el| {
-                        filters.iter().all(|f| {
-                            let nr = doc.html.tree.get(el.id()).unwrap();
-                            contains_filter::matches_filter(f, el, &nr)
-                        })
-                    })
-                    .map(|el| {
-                        store_node(NodeEntry {
-                            node_id: el.id(),
-                            doc_handle: entry.doc_handle,
-                            is_text: false,
-                        })
-                    })
-            } else {
-                None
+        }
+    };
+    with_doc_mut(doc_handle, |doc| doc.normalization = normalization).map_or(-1, |_| 0)
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn scraper_select_compiled(handle: i64, selector: i64) -> i64 {
+    match get_selector(selector) {
+        Some(sel) => select_all(handle, &sel),
+        None => -1,
+    }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn scraper_select_first_compiled(handle: i64, selector: i64) -> i64 {
+    match get_selector(selector) {
+        Some(sel) => select_first(handle, &sel),
+        None => -1,
+    }
+}
+
+/// The string a selected entry stands for: an attribute's value, a text node's text,
+/// or an element's outer HTML.
+fn entry_value(entry: &NodeEntry, doc: &DocEntry) -> Option<String> {
+    let node_ref = doc.html.tree.get(entry.node_id)?;
+    match (&entry.attr, node_ref.value()) {
+        (Some(name), Node::Element(el)) => attr_node_value(el, name).map(str::to_owned),
+        (None, Node::Text(t)) => Some(t.text.to_string()),
+        (None, Node::Element(_)) => scraper::ElementRef::wrap(node_ref).map(|el| el.html()),
+        _ => None,
+    }
+}
+
+/// The values of what `sel` selects under `handle`, or `None` for an invalid handle.
+fn select_values(handle: i64, sel: &JsoupSelector) -> Option<Vec<String>> {
+    let entries = select_entries(handle, sel, false)?;
+    Some(
+        entries
+            .iter()
+            .filter_map(|entry| with_doc(entry.doc_handle, |doc| entry_value(entry, doc)).flatten())
+            .collect(),
+    )
+}
+
+/// Hand `values` to the caller as a C string array, returning its length, or write
+/// null and return -1 if there are none to give.
+unsafe fn write_string_array(
+    values: Option<Vec<String>>,
+    out_values: *mut *mut *mut c_char,
+    out_len: *mut c_int,
+) -> c_int {
+    match values {
+        Some(values) => {
+            let len = values.len();
+            let mut boxed: Box<[*mut c_char]> = values.iter().map(|v| to_cstring(v)).collect();
+            unsafe {
+                *out_values = boxed.as_mut_ptr();
+                *out_len = len as c_int;
+            }
+            std::mem::forget(boxed);
+            len as c_int
+        }
+        None => {
+            unsafe {
+                *out_values = ptr::null_mut();
+                *out_len = 0;
+            }
+            -1
+        }
+    }
+}
+
+/// Select and read the results in one call: attribute values for a selector ending in
+/// `::attr(name)`, text for `::text`, and outer HTML for plain elements. The array is
+/// freed with `scraper_free_string_array`. Returns the number of values, or -1 if the
+/// selector is invalid or the handle unknown.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn scraper_select_values(
+    handle: i64,
+    selector: *const c_char,
+    out_values: *mut *mut *mut c_char,
+    out_len: *mut c_int,
+) -> c_int {
+    let values = unsafe { parse_selector(selector) }.and_then(|sel| select_values(handle, &sel));
+    unsafe { write_string_array(values, out_values, out_len) }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn scraper_select_values_compiled(
+    handle: i64,
+    selector: i64,
+    out_values: *mut *mut *mut c_char,
+    out_len: *mut c_int,
+) -> c_int {
+    let values = get_selector(selector).and_then(|sel| select_values(handle, &sel));
+    unsafe { write_string_array(values, out_values, out_len) }
+}
+
+// ---------------------------------------------------------------------------
+// Selection cursors
+// ---------------------------------------------------------------------------
+
+/// Open a cursor for `sel` under a document or element handle. -1 for any other handle.
+fn open_cursor(handle: i64, sel: Arc<JsoupSelector>, offset: c_int, limit: c_int) -> i64 {
+    let (doc_handle, root) = if is_document(handle) {
+        (handle, None)
+    } else {
+        match get_node(handle) {
+            Some(entry) if !entry.is_text && entry.attr.is_none() => {
+                (entry.doc_handle, Some(entry.node_id))
             }
+            _ => return -1,
+        }
+    };
+    // What `:matchText` wraps only exists during a select, so find it all now.
+    let pending = if sel.needs_prepare() {
+        match select_entries(handle, &sel, false) {
+            Some(entries) => entries.into(),
+            None => return -1,
+        }
+    } else {
+        Default::default()
+    };
+    let exhausted = sel.needs_prepare();
+    store_cursor(Cursor {
+        selector: sel,
+        doc_handle,
+        root,
+        last: None,
+        pending,
+        seen: HashSet::new(),
+        offset: offset.max(0) as usize,
+        remaining: usize::try_from(limit).ok(),
+        exhausted,
+    })
+}
+
+/// The cursor's next result, ignoring its offset and limit.
+fn cursor_advance(cursor: &mut Cursor) -> Option<NodeEntry> {
+    loop {
+        if let Some(entry) = cursor.pending.pop_front() {
+            return Some(entry);
+        }
+        if cursor.exhausted {
+            return None;
+        }
+        let found = with_doc(cursor.doc_handle, |doc| {
+            let (el, resume) = cursor.selector.next_match(doc, cursor.root, cursor.last)?;
+            let entries = project(
+                doc,
+                cursor.doc_handle,
+                vec![el.id()],
+                &cursor.selector,
+                &mut cursor.seen,
+            );
+            Some((resume, entries))
         })
+        .flatten();
+        match found {
+            Some((resume, entries)) => {
+                cursor.last = Some(resume);
+                cursor.pending.extend(entries);
+            }
+            None => cursor.exhausted = true,
+        }
+    }
+}
+
+/// The cursor's next result once its offset is skipped, while its limit allows.
+fn cursor_next(cursor: &mut Cursor) -> Option<NodeEntry> {
+    while cursor.offset > 0 {
+        cursor.offset -= 1;
+        cursor_advance(cursor)?;
+    }
+    if cursor.remaining == Some(0) {
+        return None;
+    }
+    let entry = cursor_advance(cursor)?;
+    if let Some(remaining) = &mut cursor.remaining {
+        *remaining -= 1;
+    }
+    Some(entry)
+}
+
+/// Open a cursor over what `selector` matches under a document or element handle,
+/// finding results one at a time as `scraper_cursor_next` asks for them. The first
+/// `offset` results are skipped and at most `limit` are returned (no limit if
+/// negative). Returns a handle freed with `scraper_free`, or -1 if the selector is
+/// invalid or the handle unknown.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn scraper_select_cursor(
+    handle: i64,
+    selector: *const c_char,
+    offset: c_int,
+    limit: c_int,
+) -> i64 {
+    match unsafe { parse_selector(selector) } {
+        Some(sel) => open_cursor(handle, Arc::new(sel), offset, limit),
+        None => -1,
+    }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn scraper_select_cursor_compiled(
+    handle: i64,
+    selector: i64,
+    offset: c_int,
+    limit: c_int,
+) -> i64 {
+    match get_selector(selector) {
+        Some(sel) => open_cursor(handle, sel, offset, limit),
+        None => -1,
+    }
+}
+
+/// The cursor's next result as a node handle, or -1 once it is exhausted.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn scraper_cursor_next(cursor: i64) -> i64 {
+    with_cursor_mut(cursor, cursor_next)
         .flatten()
-        .unwrap_or(-1)
+        .map_or(-1, store_node)
+}
+
+/// Move the cursor past up to `count` results without creating handles for them.
+/// Returns how many it moved past, or -1 for an unknown cursor.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn scraper_cursor_skip(cursor: i64, count: c_int) -> c_int {
+    with_cursor_mut(cursor, |cursor| {
+        let mut skipped = 0;
+        while skipped < count && cursor_next(cursor).is_some() {
+            skipped += 1;
+        }
+        skipped
+    })
+    .unwrap_or(-1)
+}
+
+// ---------------------------------------------------------------------------
+// XPath
+// ---------------------------------------------------------------------------
+
+fn xnode_to_entry(node: XNode, doc_handle: i64) -> NodeEntry {
+    match node {
+        XNode::Node(node_ref) => node_to_entry(&node_ref, doc_handle),
+        XNode::Attr(el, _) => NodeEntry {
+            node_id: el.id(),
+            doc_handle,
+            is_text: false,
+            attr: node.attr().map(|(name, _)| name.clone()),
+        },
     }
 }
 
+fn entry_to_xnode<'a>(entry: &NodeEntry, html: &'a Html) -> Option<XNode<'a>> {
+    let node_ref = html.tree.get(entry.node_id)?;
+    match &entry.attr {
+        Some(name) => {
+            let el = node_ref.value().as_element()?;
+            let index = el.attrs.iter().position(|(n, _)| n == name)?;
+            Some(XNode::Attr(node_ref, index))
+        }
+        None => Some(XNode::Node(node_ref)),
+    }
+}
+
+/// Evaluate an XPath 1.0 expression from a document or node handle. Returns a node list
+/// of the selected nodes in document order, or -1 if the expression is invalid or does
+/// not select nodes. Text results are text nodes; attribute results are attribute nodes
+/// (see `scraper_is_attribute_node`).
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn scraper_select_xpath(
+    handle: i64,
+    expr: *const c_char,
+) -> i64 {
+    let expr_str = match unsafe { cstr_to_str(expr) } {
+        Some(s) => s,
+        None => {
+            selector_error::record_unreadable();
+            return -1;
+        }
+    };
+    let xpath = match selector_error::record(expr_str, XPath::parse(expr_str)) {
+        Some(xpath) => xpath,
+        None => return -1,
+    };
+    let (doc_handle, context) = if is_document(handle) {
+        (handle, None)
+    } else {
+        match get_node(handle) {
+            Some(e) => (e.doc_handle, Some(e)),
+            None => return -1,
+        }
+    };
+    with_doc(doc_handle, |doc| {
+        let context = match &context {
+            Some(entry) => entry_to_xnode(entry, &doc.html)?,
+            None => XNode::Node(doc.html.tree.root()),
+        };
+        let entries: Vec<NodeEntry> = xpath
+            .select(&doc.html, context)
+            .into_iter()
+            .map(|node| xnode_to_entry(node, doc_handle))
+            .collect();
+        Some(store_node_list(entries, [doc_handle]))
+    })
+    .flatten()
+    .unwrap_or(-1)
+}
+
+// ---------------------------------------------------------------------------
+// Selector errors
+// ---------------------------------------------------------------------------
+
+/// Message of the last selector parse failure on this thread, or null if the last
+/// selector given to `scraper_select*`/`scraper_compile_selector` parsed. XPath
+/// expressions given to `scraper_select_xpath` are reported the same way.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn scraper_selector_error_message() -> *mut c_char {
+    selector_error::with_last_error(|e| to_cstring(&e.message)).unwrap_or(ptr::null_mut())
+}
+
+/// The part of the selector the last parse failure points at, or null.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn scraper_selector_error_token() -> *mut c_char {
+    selector_error::with_last_error(|e| to_cstring(&e.token)).unwrap_or(ptr::null_mut())
+}
+
+/// Byte offset of the failing token in the selector string, or -1.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn scraper_selector_error_offset() -> i64 {
+    selector_error::with_last_error(|e| e.offset as i64).unwrap_or(-1)
+}
+
+// ---------------------------------------------------------------------------
+// Handle errors
+// ---------------------------------------------------------------------------
+
+/// Why the last handle lookup on this thread failed, or 0 if it succeeded: 1 for a
+/// handle never handed out, 2 for a handle of the wrong kind (a node list given to
+/// `scraper_attr`), 3 for a freed handle, 4 for a handle freed with its document.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn scraper_handle_error() -> c_int {
+    handle_store::with_last_error(|e| e.error as c_int).unwrap_or(0)
+}
+
+/// Message of the last failed handle lookup on this thread, or null if it succeeded.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn scraper_handle_error_message() -> *mut c_char {
+    handle_store::with_last_error(|e| to_cstring(&e.message)).unwrap_or(ptr::null_mut())
+}
+
 // ---------------------------------------------------------------------------
 // Attributes
 // ---------------------------------------------------------------------------
@@ -303,7 +842,7 @@ pub unsafe extern "C" fn scraper_attr(
     with_node_doc(handle, |entry, doc| {
         let node_ref = doc.html.tree.get(entry.node_id)?;
         if let Node::Element(el) = node_ref.value() {
-            el.attr(key_str).map(|v| to_cstring(v))
+            attr_value(el, key_str).map(to_cstring)
         } else {
             None
         }
@@ -324,7 +863,7 @@ pub unsafe extern "C" fn scraper_has_attr(
     with_node_doc(handle, |entry, doc| {
         let node_ref = doc.html.tree.get(entry.node_id)?;
         if let Node::Element(el) = node_ref.value() {
-            Some(el.attr(key_str).is_some())
+            Some(attr_value(el, key_str).is_some())
         } else {
             Some(false)
         }
@@ -446,7 +985,7 @@ pub unsafe extern "C" fn scraper_id(handle: i64) -> *mut c_char {
         if let Node::Element(el) = node_ref.value() {
             el.attr("id")
                 .filter(|s| !s.is_empty())
-                .map(|s| to_cstring(s))
+                .map(to_cstring)
         } else {
             None
         }
@@ -530,21 +1069,21 @@ pub unsafe extern "C" fn scraper_list_size(handle: i64) -> c_int {
 #[unsafe(no_mangle)]
 pub unsafe extern "C" fn scraper_list_get(handle: i64, index: c_int) -> i64 {
     get_node_list(handle)
-        .and_then(|v| v.get(index as usize).map(|e| store_node(*e)))
+        .and_then(|v| v.get(index as usize).map(|e| store_node(e.clone())))
         .unwrap_or(-1)
 }
 
 #[unsafe(no_mangle)]
 pub unsafe extern "C" fn scraper_list_first(handle: i64) -> i64 {
     get_node_list(handle)
-        .and_then(|v| v.first().map(|e| store_node(*e)))
+        .and_then(|v| v.first().map(|e| store_node(e.clone())))
         .unwrap_or(-1)
 }
 
 #[unsafe(no_mangle)]
 pub unsafe extern "C" fn scraper_list_last(handle: i64) -> i64 {
     get_node_list(handle)
-        .and_then(|v| v.last().map(|e| store_node(*e)))
+        .and_then(|v| v.last().map(|e| store_node(e.clone())))
         .unwrap_or(-1)
 }
 
@@ -566,6 +1105,7 @@ pub unsafe extern "C" fn scraper_parent(handle: i64) -> i64 {
                 node_id: parent.id(),
                 doc_handle: entry.doc_handle,
                 is_text: false,
+                attr: None,
             }))
         } else {
             None
@@ -586,9 +1126,10 @@ pub unsafe extern "C" fn scraper_children(handle: i64) -> i64 {
                 node_id: child.id(),
                 doc_handle: entry.doc_handle,
                 is_text: false,
+                attr: None,
             })
             .collect();
-        Some(store_node_list(entries))
+        Some(store_node_list(entries, [entry.doc_handle]))
     })
     .flatten()
     .unwrap_or(-1)
@@ -606,6 +1147,7 @@ pub unsafe extern "C" fn scraper_next_sibling(handle: i64) -> i64 {
                     node_id: s.id(),
                     doc_handle: entry.doc_handle,
                     is_text: false,
+                    attr: None,
                 }));
             }
             sib = s.next_sibling();
@@ -627,6 +1169,7 @@ pub unsafe extern "C" fn scraper_prev_sibling(handle: i64) -> i64 {
                     node_id: s.id(),
                     doc_handle: entry.doc_handle,
                     is_text: false,
+                    attr: None,
                 }));
             }
             sib = s.prev_sibling();
@@ -651,9 +1194,10 @@ pub unsafe extern "C" fn scraper_siblings(handle: i64) -> i64 {
                 node_id: child.id(),
                 doc_handle: entry.doc_handle,
                 is_text: false,
+                attr: None,
             })
             .collect();
-        Some(store_node_list(entries))
+        Some(store_node_list(entries, [entry.doc_handle]))
     })
     .flatten()
     .unwrap_or(-1)
@@ -670,6 +1214,9 @@ pub unsafe extern "C" fn scraper_node_name(handle: i64) -> *mut c_char {
         return to_cstring("#root");
     }
     with_node_doc(handle, |entry, doc| {
+        if let Some(name) = &entry.attr {
+            return Some(to_cstring(&attr_node_name(name)));
+        }
         let node_ref = doc.html.tree.get(entry.node_id)?;
         Some(match node_ref.value() {
             Node::Element(el) => to_cstring(&el.name.local),
@@ -786,6 +1333,10 @@ pub unsafe extern "C" fn scraper_is_text_node(handle: i64) -> c_int {
 pub unsafe extern "C" fn scraper_parent_node(handle: i64) -> i64 {
     with_node_doc(handle, |entry, doc| {
         let node_ref = doc.html.tree.get(entry.node_id)?;
+        // An attribute's parent is its element
+        if entry.attr.is_some() {
+            return Some(store_node_from_doc(&node_ref, entry.doc_handle));
+        }
         let parent = node_ref.parent()?;
         if matches!(parent.value(), Node::Document) {
             return None;
@@ -831,6 +1382,7 @@ pub unsafe extern "C" fn scraper_text_node_handles(
                         node_id: child.id(),
                         doc_handle: entry.doc_handle,
                         is_text: true,
+                        attr: None,
                     })
                 })
                 .collect::<Vec<i64>>(),
@@ -902,6 +1454,55 @@ pub unsafe extern "C" fn scraper_text_node_is_blank(handle: i64) -> c_int {
     .unwrap_or(1)
 }
 
+// ---------------------------------------------------------------------------
+// Attribute node methods
+// ---------------------------------------------------------------------------
+
+fn attr_node_name(name: &QualName) -> String {
+    match &name.prefix {
+        Some(prefix) => format!("{}:{}", prefix, name.local),
+        None => name.local.to_string(),
+    }
+}
+
+fn attr_node_value<'a>(el: &'a Element, name: &QualName) -> Option<&'a str> {
+    el.attrs
+        .iter()
+        .find(|(n, _)| n == name)
+        .map(|(_, value)| value.as_ref())
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn scraper_is_attribute_node(handle: i64) -> c_int {
+    get_node(handle)
+        .map(|e| e.attr.is_some() as c_int)
+        .unwrap_or(0)
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn scraper_attribute_node_name(handle: i64) -> *mut c_char {
+    get_node(handle)
+        .and_then(|e| e.attr)
+        .map(|name| to_cstring(&attr_node_name(&name)))
+        .unwrap_or(ptr::null_mut())
+}
+
+/// The attribute's current value, or null if it has since been removed.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn scraper_attribute_node_value(handle: i64) -> *mut c_char {
+    with_node_doc(handle, |entry, doc| {
+        let name = entry.attr.as_ref()?;
+        let node_ref = doc.html.tree.get(entry.node_id)?;
+        if let Node::Element(el) = node_ref.value() {
+            attr_node_value(el, name).map(to_cstring)
+        } else {
+            None
+        }
+    })
+    .flatten()
+    .unwrap_or(ptr::null_mut())
+}
+
 // ---------------------------------------------------------------------------
 // Element creation
 // ---------------------------------------------------------------------------
@@ -930,6 +1531,7 @@ pub unsafe extern "C" fn scraper_create_element(tag: *const c_char) -> i64 {
         node_id: el_id,
         doc_handle,
         is_text: false,
+        attr: None,
     })
 }
 
@@ -955,6 +1557,7 @@ pub unsafe extern "C" fn scraper_create_text_node(text: *const c_char) -> i64 {
         node_id: text_id,
         doc_handle,
         is_text: true,
+        attr: None,
     })
 }
 
@@ -964,14 +1567,14 @@ pub unsafe extern "C" fn scraper_create_elements(
     count: c_int,
 ) -> i64 {
     if element_handles.is_null() || count <= 0 {
-        return store_node_list(Vec::new());
+        return store_node_list(Vec::new(), []);
     }
     let handles = unsafe { std::slice::from_raw_parts(element_handles, count as usize) };
     let entries: Vec<NodeEntry> = handles
         .iter()
         .filter_map(|&h| get_node(h))
         .collect();
-    store_node_list(entries)
+    store_node_list(entries, [])
 }
 
 // ---------------------------------------------------------------------------
@@ -983,12 +1586,188 @@ pub unsafe extern "C" fn scraper_free(handle: i64) {
     free_handle(handle);
 }
 
+/// Free every handle and end every session. With the `shared-store` feature, other
+/// isolates share the store, so this only ends the outermost session around the one
+/// the calling thread is in.
 #[unsafe(no_mangle)]
 pub unsafe extern "C" fn scraper_release_all() {
     release_all();
 }
 
+/// What became of a handle: 0 if it was never handed out, 1 if it is live, 2 if it
+/// was freed, 3 if it was freed because the document it points into was. Freeing an
+/// invalidated handle with `scraper_free` moves it to 2.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn scraper_handle_status(handle: i64) -> c_int {
+    handle_status(handle) as c_int
+}
+
 #[unsafe(no_mangle)]
 pub unsafe extern "C" fn scraper_dispose() {
     release_all();
 }
+
+// ---------------------------------------------------------------------------
+// Sessions
+// ---------------------------------------------------------------------------
+
+/// Open a handle session, nested in the one the calling thread is in, and enter it:
+/// every handle the thread creates goes into it until the thread enters another
+/// session or this one ends. Returns the session id.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn scraper_session_begin() -> i64 {
+    handle_store::begin_session()
+}
+
+/// Make a session the one the calling thread's new handles go into, or leave every
+/// session for 0. With the `shared-store` feature, an isolate that may have moved to
+/// another thread enters its session again before creating handles. Returns the
+/// session the thread was in before (0 for none), or -1 if no open session has that
+/// id.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn scraper_session_enter(id: i64) -> i64 {
+    handle_store::enter_session(id).unwrap_or(-1)
+}
+
+/// End a session and any still open inside it, freeing every handle they hold as
+/// `scraper_free` would, from whichever thread. Handles created outside any session
+/// are left alone. Returns 0, or -1 if no open session has that id.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn scraper_session_end(id: i64) -> c_int {
+    if handle_store::end_session(id) {
+        0
+    } else {
+        -1
+    }
+}
+
+/// Move a handle out of the innermost session holding it, into the session around
+/// that, or out of every session so it lives until freed. A node promoted without its
+/// document is still freed with the document. Returns 0, or -1 if no session holds
+/// the handle.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn scraper_session_promote(handle: i64) -> c_int {
+    if handle_store::promote(handle) {
+        0
+    } else {
+        -1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mutation::{scraper_remove_attr, scraper_set_attr};
+    use crate::test_support::{outer_html, parse, selector, take_string};
+
+    fn values(html: &str, selector: &str) -> Vec<String> {
+        normalized_values(html, selector, 0)
+    }
+
+    fn normalized_values(html: &str, selector_str: &str, mode: c_int) -> Vec<String> {
+        let doc = parse(html);
+        assert_eq!(unsafe { scraper_set_text_normalization(doc, mode) }, 0);
+        let values = select_values(doc, &selector(selector_str)).unwrap();
+        unsafe { scraper_free(doc) };
+        values
+    }
+
+    #[test]
+    fn attribute_keys_ignore_case_on_html_elements() {
+        let doc = parse(r#"<a Data-Src="x">a</a><svg viewBox="0 0 1 1"></svg>"#);
+        let a = select_first(doc, &selector("a"));
+        let attr = |handle, key: &CStr| take_string(unsafe { scraper_attr(handle, key.as_ptr()) });
+        assert_eq!(attr(a, c"DATA-SRC").as_deref(), Some("x"));
+        assert_eq!(unsafe { scraper_has_attr(a, c"data-SRC".as_ptr()) }, 1);
+        // Setting under another case replaces the attribute instead of adding one.
+        unsafe { scraper_set_attr(a, c"DATA-src".as_ptr(), c"y".as_ptr()) };
+        assert_eq!(outer_html(a), r#"<a data-src="y">a</a>"#);
+        unsafe { scraper_remove_attr(a, c"Data-Src".as_ptr()) };
+        assert_eq!(unsafe { scraper_has_attr(a, c"data-src".as_ptr()) }, 0);
+        // Foreign elements keep their case-sensitive names.
+        let svg = select_first(doc, &selector("svg"));
+        assert_eq!(attr(svg, c"viewBox").as_deref(), Some("0 0 1 1"));
+        assert_eq!(attr(svg, c"viewbox"), None);
+        unsafe { scraper_free(doc) };
+    }
+
+    #[test]
+    fn spaced_text_includes_the_match() {
+        let html = "<p>hello <b>world</b></p>";
+        assert_eq!(values(html, "p ::text"), ["hello ", "world"]);
+        assert_eq!(values(html, "p::text"), ["hello "]);
+        let html = "<div><p>a <b>b</b> c</p></div>";
+        assert_eq!(values(html, "p ::text"), ["a ", "b", " c"]);
+        assert_eq!(values(html, "div ::text, p ::text"), ["a ", "b", " c"]);
+    }
+
+    #[test]
+    fn spaced_attr_includes_the_match() {
+        let html = r#"<div href="/self"><a href="/child">x</a></div>"#;
+        assert_eq!(values(html, "div ::attr(href)"), ["/self", "/child"]);
+        assert_eq!(values(html, "div::attr(href)"), ["/self"]);
+    }
+
+    #[test]
+    fn attr_lookup_matches_scraper_attr() {
+        let html = r#"<a HREF="/x">x</a><svg viewBox="0 0 1 1"></svg>"#;
+        assert_eq!(values(html, "a::ATTR(href)"), ["/x"]);
+        assert_eq!(values(html, "a ::Attr(Href)"), ["/x"]);
+        // Attribute names only match case-insensitively on HTML elements.
+        assert_eq!(values(html, "svg::attr(viewBox)"), ["0 0 1 1"]);
+        assert!(values(html, "svg::attr(viewbox)").is_empty());
+    }
+
+    #[test]
+    fn normalized_whole_text_keeps_case() {
+        let html = "<p>Ａ-b</p>";
+        assert_eq!(normalized_values(html, "p:contains(a-B)", 1).len(), 1);
+        assert_eq!(
+            normalized_values(html, "p:containsWholeText(A-b)", 1).len(),
+            1
+        );
+        assert!(normalized_values(html, "p:containsWholeText(a-b)", 1).is_empty());
+        assert!(normalized_values(html, "p:containsWholeOwnText(a-b)", 2).is_empty());
+    }
+
+    #[test]
+    fn normalized_contains_folds_case_fully() {
+        let html = "<p>Die Straße</p><p>ΟΔΟΣ</p>";
+        assert_eq!(normalized_values(html, "p:contains(STRASSE)", 1).len(), 1);
+        assert!(normalized_values(html, "p:contains(STRASSE)", 0).is_empty());
+        // Final and medial sigma fold to the same letter.
+        assert_eq!(normalized_values(html, "p:contains(οδος)", 1).len(), 1);
+        assert_eq!(normalized_values(html, "p:containsOwn(ΟΔΟς)", 2).len(), 1);
+    }
+
+    #[test]
+    fn empty_lists_are_freed_with_their_document() {
+        let doc = parse("<p>x</p>");
+        let p = select_first(doc, &selector("p"));
+        let lists = [
+            select_all(doc, &selector("span")),
+            select_all(p, &selector("span")),
+            unsafe { scraper_select_xpath(doc, c"//span".as_ptr()) },
+            unsafe { scraper_children(p) },
+        ];
+        let nested = select_all(lists[0], &selector("b"));
+        unsafe { scraper_free(doc) };
+        for list in lists.into_iter().chain([nested]) {
+            assert_eq!(unsafe { scraper_handle_status(list) }, 3);
+        }
+    }
+
+    #[test]
+    fn sessions_report_unknown_ids() {
+        let session = unsafe { scraper_session_begin() };
+        let doc = parse("<p>x</p>");
+        assert_eq!(unsafe { scraper_session_enter(session + 1) }, -1);
+        assert_eq!(unsafe { scraper_session_enter(0) }, session);
+        assert_eq!(unsafe { scraper_session_promote(doc) }, 0);
+        assert_eq!(unsafe { scraper_session_promote(doc) }, -1);
+        assert_eq!(unsafe { scraper_session_end(session) }, 0);
+        assert_eq!(unsafe { scraper_session_end(session) }, -1);
+        assert_eq!(unsafe { scraper_handle_status(doc) }, 1);
+        unsafe { scraper_free(doc) };
+    }
+}
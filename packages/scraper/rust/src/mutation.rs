@@ -1,11 +1,18 @@
-use crate::handle_store::with_node_doc_mut;
-use crate::cstr_to_str;
+use crate::handle_store::{with_node_doc_mut, DocEntry, NodeEntry};
+use crate::{attr_key_matches, cstr_to_str};
 use ego_tree::NodeId;
 use html5ever::Attribute;
 use markup5ever::{ns, LocalName, QualName};
-use scraper::{Html, Node};
+use scraper::{Html, Node, StrTendril};
 use std::ffi::c_char;
-use tendril::StrTendril;
+
+/// Change a node's document, then drop the indexes built over its tree.
+fn mutate(handle: i64, f: impl FnOnce(&NodeEntry, &mut DocEntry)) {
+    with_node_doc_mut(handle, |entry, doc| {
+        f(entry, doc);
+        doc.tree_changed();
+    });
+}
 
 fn make_qname(local: &str) -> QualName {
     QualName::new(None, ns!(), LocalName::from(local))
@@ -25,7 +32,7 @@ fn rebuild_element(
                 .iter()
                 .map(|(k, v)| Attribute {
                     name: k.clone(),
-                    value: v.clone(),
+                    value: (**v).into(),
                 })
                 .collect(),
         );
@@ -34,30 +41,29 @@ fn rebuild_element(
 }
 
 /// Set or insert an attribute value, rebuilding the Element to invalidate caches.
+/// Like Jsoup, an HTML element's attribute name is lower-cased and replaces any
+/// existing attribute that differs from it only in case.
 fn upsert_attr(node_mut: &mut ego_tree::NodeMut<Node>, key: &str, value: &str) {
     if let Node::Element(ref el) = node_mut.value() {
         let mut new_attrs: Vec<_> = el.attrs.clone();
-        let qname = make_qname(key);
-        match new_attrs.binary_search_by(|attr| attr.0.cmp(&qname)) {
-            Ok(idx) => {
-                new_attrs[idx].1 = value.into();
-            }
-            Err(idx) => {
-                new_attrs.insert(idx, (qname, value.into()));
-            }
-        }
+        new_attrs.retain(|(name, _)| !attr_key_matches(el, name, key));
+        let qname = if el.name.ns == ns!(html) {
+            make_qname(&key.to_ascii_lowercase())
+        } else {
+            make_qname(key)
+        };
+        let idx = new_attrs.partition_point(|attr| attr.0 < qname);
+        new_attrs.insert(idx, (qname, value.into()));
         rebuild_element(node_mut, new_attrs);
     }
 }
 
-/// Remove an attribute by local name, rebuilding the Element to invalidate caches.
+/// Remove an attribute by local name, ignoring case on HTML elements, rebuilding the
+/// Element to invalidate caches.
 fn remove_attr(node_mut: &mut ego_tree::NodeMut<Node>, key: &str) {
     if let Node::Element(ref el) = node_mut.value() {
-        let qname = make_qname(key);
         let mut new_attrs: Vec<_> = el.attrs.clone();
-        if let Ok(idx) = new_attrs.binary_search_by(|attr| attr.0.cmp(&qname)) {
-            new_attrs.remove(idx);
-        }
+        new_attrs.retain(|(name, _)| !attr_key_matches(el, name, key));
         rebuild_element(node_mut, new_attrs);
     }
 }
@@ -80,7 +86,7 @@ pub unsafe extern "C" fn scraper_set_attr(
         Some(s) => s,
         None => return,
     };
-    with_node_doc_mut(handle, |entry, doc| {
+    mutate(handle, |entry, doc| {
         if let Some(mut node_mut) = doc.html.tree.get_mut(entry.node_id) {
             upsert_attr(&mut node_mut, key_str, val_str);
         }
@@ -96,7 +102,7 @@ pub unsafe extern "C" fn scraper_remove_attr(
         Some(s) => s,
         None => return,
     };
-    with_node_doc_mut(handle, |entry, doc| {
+    mutate(handle, |entry, doc| {
         if let Some(mut node_mut) = doc.html.tree.get_mut(entry.node_id) {
             remove_attr(&mut node_mut, key_str);
         }
@@ -116,7 +122,7 @@ pub unsafe extern "C" fn scraper_add_class(
         Some(s) => s,
         None => return,
     };
-    with_node_doc_mut(handle, |entry, doc| {
+    mutate(handle, |entry, doc| {
         if let Some(mut node_mut) = doc.html.tree.get_mut(entry.node_id) {
             let current = if let Node::Element(ref el) = node_mut.value() {
                 el.attr("class").unwrap_or("").to_string()
@@ -144,7 +150,7 @@ pub unsafe extern "C" fn scraper_remove_class(
         Some(s) => s,
         None => return,
     };
-    with_node_doc_mut(handle, |entry, doc| {
+    mutate(handle, |entry, doc| {
         if let Some(mut node_mut) = doc.html.tree.get_mut(entry.node_id) {
             let current = if let Node::Element(ref el) = node_mut.value() {
                 el.attr("class").unwrap_or("").to_string()
@@ -177,7 +183,7 @@ pub unsafe extern "C" fn scraper_set_text(
         Some(s) => s,
         None => return,
     };
-    with_node_doc_mut(handle, |entry, doc| {
+    mutate(handle, |entry, doc| {
         remove_all_children(&mut doc.html, entry.node_id);
         let text_node = Node::Text(scraper::node::Text {
             text: text_str.into(),
@@ -197,7 +203,7 @@ pub unsafe extern "C" fn scraper_set_html(
         Some(s) => s,
         None => return,
     };
-    with_node_doc_mut(handle, |entry, doc| {
+    mutate(handle, |entry, doc| {
         remove_all_children(&mut doc.html, entry.node_id);
         let fragment = Html::parse_fragment(html_str);
         transplant_children(&fragment, &mut doc.html, entry.node_id);
@@ -206,7 +212,7 @@ pub unsafe extern "C" fn scraper_set_html(
 
 #[unsafe(no_mangle)]
 pub unsafe extern "C" fn scraper_remove_element(handle: i64) {
-    with_node_doc_mut(handle, |entry, doc| {
+    mutate(handle, |entry, doc| {
         if let Some(mut node_mut) = doc.html.tree.get_mut(entry.node_id) {
             node_mut.detach();
         }
@@ -215,7 +221,7 @@ pub unsafe extern "C" fn scraper_remove_element(handle: i64) {
 
 #[unsafe(no_mangle)]
 pub unsafe extern "C" fn scraper_remove_node(handle: i64) {
-    with_node_doc_mut(handle, |entry, doc| {
+    mutate(handle, |entry, doc| {
         if let Some(mut node_mut) = doc.html.tree.get_mut(entry.node_id) {
             node_mut.detach();
         }
@@ -231,7 +237,7 @@ pub unsafe extern "C" fn scraper_prepend(
         Some(s) => s,
         None => return,
     };
-    with_node_doc_mut(handle, |entry, doc| {
+    mutate(handle, |entry, doc| {
         let fragment = Html::parse_fragment(html_str);
         let first_child = doc
             .html
@@ -308,7 +314,7 @@ pub unsafe extern "C" fn scraper_append(
         Some(s) => s,
         None => return,
     };
-    with_node_doc_mut(handle, |entry, doc| {
+    mutate(handle, |entry, doc| {
         let fragment = Html::parse_fragment(html_str);
         transplant_children(&fragment, &mut doc.html, entry.node_id);
     });
@@ -327,7 +333,7 @@ pub unsafe extern "C" fn scraper_set_text_node_text(
         Some(s) => s,
         None => return,
     };
-    with_node_doc_mut(handle, |entry, doc| {
+    mutate(handle, |entry, doc| {
         if let Some(mut node_mut) = doc.html.tree.get_mut(entry.node_id) {
             if let Node::Text(ref mut t) = node_mut.value() {
                 t.text = text_str.into();
@@ -0,0 +1,58 @@
+use icu_casemap::CaseMapperBorrowed;
+use icu_normalizer::ComposingNormalizerBorrowed;
+use std::ffi::c_int;
+
+/// How the :contains family compares text, set per document or per compiled selector.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub(crate) enum Normalization {
+    /// Jsoup's own comparison.
+    #[default]
+    None,
+    /// NFKC on both sides, so `第１２話` finds `第12話` and composed characters find
+    /// their decomposed forms, then full case folding for the filters Jsoup runs
+    /// without case, so `STRASSE` finds `straße` and `ΟΔΟΣ` finds `οδος`.
+    Nfkc,
+    /// As `Nfkc`, with katakana also folded to hiragana.
+    NfkcKana,
+}
+
+impl Normalization {
+    /// The mode an FFI caller asked for: 0, 1 or 2, in declaration order.
+    pub(crate) fn from_raw(mode: c_int) -> Option<Self> {
+        match mode {
+            0 => Some(Normalization::None),
+            1 => Some(Normalization::Nfkc),
+            2 => Some(Normalization::NfkcKana),
+            _ => None,
+        }
+    }
+
+    /// `s` in this mode's normal form, case-folded too if `lower`.
+    pub(crate) fn apply(self, s: &str, lower: bool) -> String {
+        if self == Normalization::None {
+            return s.to_owned();
+        }
+        let nfkc = ComposingNormalizerBorrowed::new_nfkc();
+        let normalized = nfkc.normalize(s);
+        let folded = if lower {
+            // Folding can leave text that is no longer in NFKC, so normalize again.
+            nfkc.normalize(&CaseMapperBorrowed::new().fold_string(&normalized))
+                .into_owned()
+        } else {
+            normalized.into_owned()
+        };
+        if self == Normalization::NfkcKana {
+            folded.chars().map(katakana_to_hiragana).collect()
+        } else {
+            folded
+        }
+    }
+}
+
+/// The hiragana for a katakana letter or iteration mark; other characters as they are.
+fn katakana_to_hiragana(c: char) -> char {
+    match c {
+        'ァ'..='ヶ' | 'ヽ' | 'ヾ' => char::from_u32(c as u32 - 0x60).unwrap_or(c),
+        _ => c,
+    }
+}
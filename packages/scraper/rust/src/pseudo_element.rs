@@ -0,0 +1,102 @@
+use crate::selector_error::SelectorError;
+use crate::tokenizer::{unquote, Scanner};
+
+/// A Scrapy-style pseudo-element ending a selector: what to take from each match.
+#[derive(Clone, PartialEq, Eq)]
+pub(crate) enum PseudoElement {
+    /// `::text` — the element's text node children.
+    Text,
+    /// `::attr(name)` — the named attribute, where the element has it, looked up as
+    /// `scraper_attr` does.
+    Attr(String),
+}
+
+#[derive(Clone, PartialEq, Eq)]
+pub(crate) struct Projection {
+    pub pseudo: PseudoElement,
+    /// Taken from each match and every element below it rather than the match alone,
+    /// for `p ::text`, which Scrapy reads as descendant-or-self.
+    pub descendants: bool,
+}
+
+const TEXT: &str = "::text";
+const ATTR: &str = "::attr(";
+
+/// Whether `at` is outside any group or string of `s`.
+fn at_top_level(s: &str, at: usize) -> bool {
+    Scanner::new(s).any(|(i, _, depth)| i == at && depth == 0)
+}
+
+/// A pseudo-element and the text it was written as.
+type Written<'a> = (PseudoElement, &'a str);
+
+/// Split a trailing pseudo-element off one alternative, returning the rest of the
+/// selector and the pseudo-element.
+fn split_one(alt: &str) -> Result<(&str, Option<Written<'_>>), SelectorError<'_>> {
+    let trimmed = alt.trim_end();
+    let text_at = trimmed.len().saturating_sub(TEXT.len());
+    if trimmed.is_char_boundary(text_at)
+        && trimmed[text_at..].eq_ignore_ascii_case(TEXT)
+        && at_top_level(trimmed, text_at)
+    {
+        return Ok((
+            &trimmed[..text_at],
+            Some((PseudoElement::Text, &trimmed[text_at..])),
+        ));
+    }
+    if trimmed.ends_with(')') {
+        // Lower-casing ASCII keeps byte offsets, so `open` indexes `trimmed` too.
+        if let Some(open) = trimmed
+            .to_ascii_lowercase()
+            .rfind(ATTR)
+            .filter(|&open| at_top_level(trimmed, open))
+        {
+            let raw = &trimmed[open..];
+            let name = unquote(&trimmed[open + ATTR.len()..trimmed.len() - 1]).trim();
+            if name.is_empty() {
+                return Err(SelectorError::new("Attribute name must not be empty", raw));
+            }
+            return Ok((
+                &trimmed[..open],
+                Some((PseudoElement::Attr(name.to_string()), raw)),
+            ));
+        }
+    }
+    Ok((alt, None))
+}
+
+/// Split the trailing pseudo-element off every alternative of a selector. Either none
+/// of them has one, or all end in the same one. A selector left empty (`::text`)
+/// stands for the element the select was run on and comes back as `scope`.
+pub(crate) fn split<'a>(
+    alternatives: &[&'a str],
+    scope: &'static str,
+) -> Result<(Vec<&'a str>, Option<Projection>), SelectorError<'a>> {
+    let mut selectors = Vec::with_capacity(alternatives.len());
+    let mut projection: Option<Option<Projection>> = None;
+    for &alt in alternatives {
+        let (rest, pseudo) = split_one(alt)?;
+        let has_pseudo = pseudo.is_some();
+        let this = pseudo.as_ref().map(|(pseudo, _)| Projection {
+            pseudo: pseudo.clone(),
+            descendants: !rest.trim().is_empty() && rest.ends_with(char::is_whitespace),
+        });
+        match &projection {
+            Some(first) if *first != this => {
+                return Err(SelectorError::new(
+                    "Every alternative must end in the same pseudo-element",
+                    pseudo.map_or(alt.trim(), |(_, raw)| raw),
+                ));
+            }
+            Some(_) => {}
+            None => projection = Some(this),
+        }
+        let rest = rest.trim_end();
+        selectors.push(if has_pseudo && rest.is_empty() {
+            scope
+        } else {
+            rest
+        });
+    }
+    Ok((selectors, projection.flatten()))
+}
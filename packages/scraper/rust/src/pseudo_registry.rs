@@ -0,0 +1,200 @@
+use crate::jsoup_selector::MatchContext;
+use crate::selector_error::SelectorError;
+use crate::tokenizer::{unquote, Part};
+use scraper::ElementRef;
+
+/// A pseudo-class the bridge evaluates itself, beyond the Jsoup ones. Compiled
+/// selectors are shared between threads by the `shared-store` feature.
+pub(crate) trait PseudoClass: Send + Sync {
+    fn matches(&self, el: &ElementRef, ctx: &MatchContext) -> bool;
+
+    /// Rough evaluation cost, on the scale of the Jsoup filters: 0 for structure, 1 for
+    /// attributes, 3 for reading the subtree's text.
+    fn cost(&self) -> u8;
+}
+
+/// Builds a pseudo-class from its argument, which is empty for a bare `:name`.
+type Build = for<'a> fn(&'a str) -> Result<Box<dyn PseudoClass>, SelectorError<'a>>;
+
+/// How a registered pseudo-class is written and built.
+struct PseudoDef {
+    name: &'static str,
+    /// Whether it is written `:name(arg)` rather than `:name`.
+    takes_arg: bool,
+    build: Build,
+}
+
+/// Every registered pseudo-class, looked up by name (ASCII case-insensitive) while a
+/// selector is parsed. A new one only needs an entry here.
+const REGISTRY: &[PseudoDef] = &[
+    PseudoDef {
+        name: "blank",
+        takes_arg: false,
+        build: |_| Ok(Box::new(Blank)),
+    },
+    PseudoDef {
+        name: "has-text",
+        takes_arg: false,
+        build: |_| Ok(Box::new(HasText)),
+    },
+    PseudoDef {
+        name: "attr-nonempty",
+        takes_arg: true,
+        build: |arg| {
+            let name = unquote(arg).trim();
+            if name.is_empty() {
+                return Err(SelectorError::new("Attribute name must not be empty", arg));
+            }
+            Ok(Box::new(AttrNonEmpty(name.to_string())))
+        },
+    },
+    PseudoDef {
+        name: "own-index",
+        takes_arg: true,
+        build: |arg| {
+            let index = arg
+                .trim()
+                .parse::<usize>()
+                .map_err(|_| SelectorError::new("Index must be numeric", arg))?;
+            Ok(Box::new(OwnIndex(index)))
+        },
+    },
+];
+
+/// Parse `part` if it is a registered pseudo-class. Fails if it is written with an
+/// argument it does not take, or without one it needs.
+pub(crate) fn parse_registered<'a>(
+    part: &Part<'a>,
+) -> Option<Result<Box<dyn PseudoClass>, SelectorError<'a>>> {
+    let Part::Pseudo { name, arg, raw } = part else {
+        return None;
+    };
+    let def = REGISTRY
+        .iter()
+        .find(|def| def.name.eq_ignore_ascii_case(name))?;
+    Some(match (def.takes_arg, arg) {
+        (true, Some(arg)) => (def.build)(arg),
+        (false, None) => (def.build)(""),
+        (true, None) => Err(SelectorError::new(
+            format!("Pseudo-class :{} needs an argument", def.name),
+            raw,
+        )),
+        (false, Some(_)) => Err(SelectorError::new(
+            format!("Pseudo-class :{} takes no argument", def.name),
+            raw,
+        )),
+    })
+}
+
+/// `:blank` — the element's text is empty or only whitespace.
+struct Blank;
+
+impl PseudoClass for Blank {
+    fn matches(&self, el: &ElementRef, ctx: &MatchContext) -> bool {
+        ctx.texts.text(el).is_empty()
+    }
+
+    fn cost(&self) -> u8 {
+        3
+    }
+}
+
+/// `:has-text` — the element's whitespace-normalized text is not empty.
+struct HasText;
+
+impl PseudoClass for HasText {
+    fn matches(&self, el: &ElementRef, ctx: &MatchContext) -> bool {
+        !ctx.texts.text(el).is_empty()
+    }
+
+    fn cost(&self) -> u8 {
+        3
+    }
+}
+
+/// `:attr-nonempty(name)` — the element has the attribute, with a value that is not
+/// only whitespace. The name is matched as `scraper_attr` matches it.
+struct AttrNonEmpty(String);
+
+impl PseudoClass for AttrNonEmpty {
+    fn matches(&self, el: &ElementRef, _: &MatchContext) -> bool {
+        crate::attr_value(el.value(), &self.0).is_some_and(|value| !value.trim().is_empty())
+    }
+
+    fn cost(&self) -> u8 {
+        1
+    }
+}
+
+/// `:own-index(n)` — the element is the nth of its parent's children with its tag,
+/// counting from 0 (a zero-based `:nth-of-type`).
+struct OwnIndex(usize);
+
+impl PseudoClass for OwnIndex {
+    fn matches(&self, el: &ElementRef, _: &MatchContext) -> bool {
+        let name = &el.value().name;
+        el.prev_siblings()
+            .filter_map(ElementRef::wrap)
+            .filter(|sibling| sibling.value().name == *name)
+            .count()
+            == self.0
+    }
+
+    fn cost(&self) -> u8 {
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::jsoup_selector::JsoupSelector;
+    use crate::test_support::{ids, parse};
+
+    const HTML: &str = r#"<div id="d1"><p id="p1"> </p><p id="p2">x</p><span id="s1" title=" "></span><span id="s2" title="t"></span><p id="p3" title=""><b id="b1">y</b></p></div>"#;
+
+    #[test]
+    fn registered_pseudo_classes() {
+        let doc = parse(HTML);
+        assert_eq!(ids(doc, "p:blank"), ["p1"]);
+        assert_eq!(ids(doc, "p:has-text"), ["p2", "p3"]);
+        assert_eq!(ids(doc, "div :attr-nonempty(title)"), ["s2"]);
+        assert_eq!(ids(doc, "span:attr-nonempty('TITLE')"), ["s2"]);
+        assert_eq!(ids(doc, "p:own-index(1)"), ["p2"]);
+        assert_eq!(ids(doc, "div > :own-index(0)"), ["p1", "s1"]);
+        assert_eq!(ids(doc, "div :BLANK:own-index(0)"), ["p1", "s1"]);
+        assert_eq!(ids(doc, "p:not(:blank)"), ["p2", "p3"]);
+    }
+
+    #[test]
+    fn argument_errors() {
+        fn error(query: &str) -> (String, String) {
+            let err = JsoupSelector::parse(query).err().unwrap();
+            (err.message, err.token.to_string())
+        }
+        assert_eq!(
+            error("p:blank(1)"),
+            (
+                "Pseudo-class :blank takes no argument".to_string(),
+                ":blank(1)".to_string()
+            )
+        );
+        assert_eq!(
+            error("p:own-index"),
+            (
+                "Pseudo-class :own-index needs an argument".to_string(),
+                ":own-index".to_string()
+            )
+        );
+        assert_eq!(
+            error("p:own-index(x)"),
+            ("Index must be numeric".to_string(), "x".to_string())
+        );
+        assert_eq!(
+            error("p:attr-nonempty('')"),
+            (
+                "Attribute name must not be empty".to_string(),
+                "''".to_string()
+            )
+        );
+    }
+}
@@ -0,0 +1,73 @@
+use std::cell::RefCell;
+
+/// Why a selector could not be parsed.
+pub(crate) struct SelectorError<'a> {
+    pub message: String,
+    /// The offending slice of the selector string; empty if the input ended early.
+    pub token: &'a str,
+}
+
+impl<'a> SelectorError<'a> {
+    pub(crate) fn new(message: impl Into<String>, token: &'a str) -> Self {
+        SelectorError {
+            message: message.into(),
+            token,
+        }
+    }
+}
+
+/// The last selector parse error on this thread, as reported through the FFI.
+pub(crate) struct LastSelectorError {
+    pub message: String,
+    pub token: String,
+    /// Byte offset of `token` in the selector string.
+    pub offset: usize,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<LastSelectorError>> = const { RefCell::new(None) };
+}
+
+/// Byte offset of `inner` in `outer` when it is a slice of it, or else where its text
+/// first occurs.
+pub(crate) fn offset_in(outer: &str, inner: &str) -> usize {
+    (inner.as_ptr() as usize)
+        .checked_sub(outer.as_ptr() as usize)
+        .filter(|&offset| offset + inner.len() <= outer.len())
+        .or_else(|| outer.find(inner))
+        .unwrap_or(0)
+}
+
+/// Record the outcome of parsing `selector`, replacing the previous error (or clearing it
+/// on success), and return the parsed value.
+pub(crate) fn record<T>(selector: &str, result: Result<T, SelectorError>) -> Option<T> {
+    let (value, error) = match result {
+        Ok(value) => (Some(value), None),
+        Err(err) => (
+            None,
+            Some(LastSelectorError {
+                offset: offset_in(selector, err.token),
+                token: err.token.to_string(),
+                message: err.message,
+            }),
+        ),
+    };
+    LAST_ERROR.with(|last| *last.borrow_mut() = error);
+    value
+}
+
+/// Record an error for a selector argument that could not be read at all.
+pub(crate) fn record_unreadable() {
+    LAST_ERROR.with(|last| {
+        *last.borrow_mut() = Some(LastSelectorError {
+            message: "Selector must be a non-null UTF-8 string".to_string(),
+            token: String::new(),
+            offset: 0,
+        })
+    });
+}
+
+/// Access the last selector parse error, if the last parse failed.
+pub(crate) fn with_last_error<R>(f: impl FnOnce(&LastSelectorError) -> R) -> Option<R> {
+    LAST_ERROR.with(|last| last.borrow().as_ref().map(f))
+}
@@ -0,0 +1,48 @@
+//! Helpers shared by the unit tests.
+
+use crate::handle_store::with_doc;
+use crate::jsoup_selector::JsoupSelector;
+use crate::{scraper_free_string, scraper_outer_html, scraper_parse, select_entries};
+use std::ffi::{c_char, CStr, CString};
+
+/// Parse `html` as a document and return its handle.
+pub(crate) fn parse(html: &str) -> i64 {
+    let html = CString::new(html).unwrap();
+    unsafe { scraper_parse(html.as_ptr(), c"".as_ptr()) }
+}
+
+/// Parse a selector that is expected to be valid.
+pub(crate) fn selector(query: &str) -> JsoupSelector {
+    JsoupSelector::parse(query).ok().unwrap()
+}
+
+/// Take a string the FFI handed out, freeing it.
+pub(crate) fn take_string(s: *mut c_char) -> Option<String> {
+    if s.is_null() {
+        return None;
+    }
+    let owned = unsafe { CStr::from_ptr(s) }.to_str().unwrap().to_string();
+    unsafe { scraper_free_string(s) };
+    Some(owned)
+}
+
+/// The outer HTML of an element handle.
+pub(crate) fn outer_html(handle: i64) -> String {
+    take_string(unsafe { scraper_outer_html(handle) }).unwrap()
+}
+
+/// The ids of the elements `query` selects under `handle`, in result order.
+pub(crate) fn ids(handle: i64, query: &str) -> Vec<String> {
+    select_entries(handle, &selector(query), false)
+        .unwrap()
+        .iter()
+        .map(|entry| {
+            with_doc(entry.doc_handle, |doc| {
+                let node = doc.html.tree.get(entry.node_id).unwrap();
+                let el = node.value().as_element().unwrap();
+                el.id().unwrap_or_default().to_string()
+            })
+            .unwrap()
+        })
+        .collect()
+}
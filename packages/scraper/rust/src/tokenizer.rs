@@ -0,0 +1,255 @@
+use crate::selector_error::SelectorError;
+use std::str::CharIndices;
+
+/// Walks a selector string, tracking `()`/`[]` nesting, CSS strings and backslash escapes.
+///
+/// Yields every structural character (outside strings and not escaped) with its byte
+/// offset and the nesting depth in front of it. A backslash and the opening quote of a
+/// string are yielded; the escaped character and the rest of the string are not.
+pub(crate) struct Scanner<'a> {
+    chars: CharIndices<'a>,
+    /// Open `(`/`[` groups, innermost last.
+    stack: Vec<char>,
+    /// Last structural non-whitespace character. A quote only opens a string at the
+    /// start of a value (after `(`, `[`, `=` or `,`), so `:contains(Don't)` still works.
+    prev: Option<char>,
+    balanced: bool,
+}
+
+impl<'a> Scanner<'a> {
+    pub(crate) fn new(s: &'a str) -> Self {
+        Scanner {
+            chars: s.char_indices(),
+            stack: Vec::new(),
+            prev: None,
+            balanced: true,
+        }
+    }
+
+    /// Whether everything scanned so far has closed every group and string it opened.
+    pub(crate) fn is_balanced(&self) -> bool {
+        self.balanced && self.stack.is_empty()
+    }
+
+    fn skip_string(&mut self, quote: char) {
+        while let Some((_, c)) = self.chars.next() {
+            match c {
+                '\\' => {
+                    self.chars.next();
+                }
+                c if c == quote => return,
+                _ => {}
+            }
+        }
+        self.balanced = false;
+    }
+}
+
+impl Iterator for Scanner<'_> {
+    type Item = (usize, char, usize);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (i, c) = self.chars.next()?;
+        let depth = self.stack.len();
+        match c {
+            '\\' => {
+                self.chars.next();
+            }
+            '"' | '\'' if self.prev.is_none_or(|p| matches!(p, '(' | '[' | '=' | ',')) => {
+                self.skip_string(c)
+            }
+            '(' => self.stack.push(')'),
+            '[' => self.stack.push(']'),
+            // A closer that does not match the innermost open group.
+            ')' | ']' if self.stack.pop() != Some(c) => self.balanced = false,
+            _ => {}
+        }
+        if !c.is_whitespace() {
+            self.prev = Some(c);
+        }
+        Some((i, c, depth))
+    }
+}
+
+/// Byte offset of the `)`/`]` closing the group opened at `open`.
+pub(crate) fn group_end(s: &str, open: usize) -> Result<usize, SelectorError<'_>> {
+    let mut scanner = Scanner::new(&s[open..]);
+    while let Some((i, c, depth)) = scanner.next() {
+        if depth == 1 && matches!(c, ')' | ']') && scanner.is_balanced() {
+            return Ok(open + i);
+        }
+    }
+    Err(unbalanced(&s[open..]))
+}
+
+/// The error Jsoup raises for an unclosed group or string.
+pub(crate) fn unbalanced(rest: &str) -> SelectorError<'_> {
+    SelectorError::new(format!("Did not find balanced marker at '{rest}'"), rest)
+}
+
+/// Split `s` on `sep` where it is not nested inside `()`, `[]` or a string.
+pub(crate) fn split_top_level(s: &str, sep: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    for (i, c, depth) in Scanner::new(s) {
+        if c == sep && depth == 0 {
+            parts.push(&s[start..i]);
+            start = i + c.len_utf8();
+        }
+    }
+    parts.push(&s[start..]);
+    parts
+}
+
+/// One top-level piece of a compound selector.
+pub(crate) enum Part<'a> {
+    /// `:name` or `:name(arg)`; `arg` is the raw text between the parentheses.
+    Pseudo {
+        name: &'a str,
+        arg: Option<&'a str>,
+        raw: &'a str,
+    },
+    /// `[content]`; `content` is the raw text between the brackets.
+    Attr { content: &'a str, raw: &'a str },
+    /// Any other run of text (type, id, class, pseudo-elements), left for scraper.
+    Other(&'a str),
+}
+
+impl<'a> Part<'a> {
+    pub(crate) fn raw(&self) -> &'a str {
+        match self {
+            Part::Pseudo { raw, .. } | Part::Attr { raw, .. } => raw,
+            Part::Other(raw) => raw,
+        }
+    }
+
+    /// The argument of `:name(arg)` if this is that pseudo-class (names are ASCII
+    /// case-insensitive, as in CSS and Jsoup).
+    pub(crate) fn pseudo_arg(&self, pseudo: &str) -> Option<&'a str> {
+        match self {
+            Part::Pseudo {
+                name,
+                arg: Some(arg),
+                ..
+            } if name.eq_ignore_ascii_case(pseudo) => Some(arg),
+            _ => None,
+        }
+    }
+}
+
+fn ident_len(s: &str) -> usize {
+    s.char_indices()
+        .find(|&(_, c)| !(c.is_alphanumeric() || c == '-' || c == '_' || !c.is_ascii()))
+        .map_or(s.len(), |(i, _)| i)
+}
+
+/// Byte offset just past the string whose opening quote is at `open`.
+fn string_end(s: &str, open: usize) -> Result<usize, SelectorError<'_>> {
+    let quote = s[open..]
+        .chars()
+        .next()
+        .ok_or_else(|| unbalanced(&s[open..]))?;
+    let mut chars = s[open + 1..].char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            c if c == quote => return Ok(open + 1 + i + 1),
+            _ => {}
+        }
+    }
+    Err(unbalanced(&s[open..]))
+}
+
+/// Split a compound selector into its top-level parts. Fails if a group or string
+/// is left open, or closed without being opened.
+pub(crate) fn tokenize<'a>(s: &'a str) -> Result<Vec<Part<'a>>, SelectorError<'a>> {
+    let mut parts = Vec::new();
+    let mut other = 0;
+    let mut i = 0;
+
+    let flush = |parts: &mut Vec<Part<'a>>, from: usize, to: usize| {
+        if from < to {
+            parts.push(Part::Other(&s[from..to]));
+        }
+    };
+
+    while let Some(c) = s[i..].chars().next() {
+        match c {
+            '\\' => {
+                i += 1 + s[i + 1..].chars().next().map_or(0, char::len_utf8);
+            }
+            '[' => {
+                let end = group_end(s, i)?;
+                flush(&mut parts, other, i);
+                parts.push(Part::Attr {
+                    content: &s[i + 1..end],
+                    raw: &s[i..=end],
+                });
+                i = end + 1;
+                other = i;
+            }
+            // Pseudo-element: kept in the surrounding text, arguments and all.
+            ':' if s[i + 1..].starts_with(':') => i += 2,
+            ':' => {
+                let name_end = i + 1 + ident_len(&s[i + 1..]);
+                if name_end == i + 1 {
+                    i += 1;
+                    continue;
+                }
+                let (arg, end) = if s[name_end..].starts_with('(') {
+                    let close = group_end(s, name_end)?;
+                    (Some(&s[name_end + 1..close]), close + 1)
+                } else {
+                    (None, name_end)
+                };
+                flush(&mut parts, other, i);
+                parts.push(Part::Pseudo {
+                    name: &s[i + 1..name_end],
+                    arg,
+                    raw: &s[i..end],
+                });
+                i = end;
+                other = i;
+            }
+            '(' => i = group_end(s, i)? + 1,
+            ')' | ']' => {
+                return Err(SelectorError::new(
+                    format!("Unexpected '{c}' without a matching opening bracket"),
+                    &s[i..i + 1],
+                ))
+            }
+            '"' | '\'' => i = string_end(s, i)?,
+            _ => i += c.len_utf8(),
+        }
+    }
+    flush(&mut parts, other, s.len());
+    Ok(parts)
+}
+
+/// Strip one pair of matching quotes around a pseudo argument, as Jsoup does.
+/// Unquoted arguments are returned untouched.
+pub(crate) fn unquote(arg: &str) -> &str {
+    let trimmed = arg.trim();
+    for quote in ['"', '\''] {
+        if trimmed.len() >= 2 && trimmed.starts_with(quote) && trimmed.ends_with(quote) {
+            return &trimmed[1..trimmed.len() - 1];
+        }
+    }
+    arg
+}
+
+/// Drop the backslash from every escape sequence (`\)` becomes `)`).
+pub(crate) fn unescape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            out.extend(chars.next());
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
@@ -1,7 +1,7 @@
 use crate::handle_store::{get_node, is_document, with_doc, with_doc_mut, with_node_doc};
-use crate::{cstr_to_str, to_cstring};
+use crate::{attr_value, cstr_to_str, to_cstring};
 use scraper::Node;
-use std::ffi::{c_char, c_int};
+use std::ffi::c_char;
 use std::ptr;
 use url::Url;
 
@@ -32,7 +32,7 @@ pub unsafe extern "C" fn scraper_node_abs_url(
     with_node_doc(handle, |entry, doc| {
         let node_ref = doc.html.tree.get(entry.node_id)?;
         let attr_val = if let Node::Element(el) = node_ref.value() {
-            el.attr(key_str)?
+            attr_value(el, key_str)?
         } else {
             return Some(to_cstring(""));
         };
@@ -0,0 +1,673 @@
+use crate::element_index::document_order;
+use crate::selector_error::SelectorError;
+use crate::xpath_parser::{
+    self, is_xpath_space, ArithOp, Axis, CompareOp, Expr, Function, NodeTest, Step,
+};
+use ego_tree::{NodeId, NodeRef};
+use markup5ever::QualName;
+use scraper::{Html, Node};
+use std::cell::OnceCell;
+use std::collections::{HashMap, HashSet};
+use std::iter;
+
+/// A node in the XPath data model: a tree node, or an element's attribute by index.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum XNode<'a> {
+    Node(NodeRef<'a, Node>),
+    Attr(NodeRef<'a, Node>, usize),
+}
+
+impl<'a> XNode<'a> {
+    /// The attribute's name and value, if this is an attribute node.
+    pub(crate) fn attr(self) -> Option<&'a (QualName, scraper::StrTendril)> {
+        match self {
+            XNode::Attr(el, i) => el.value().as_element()?.attrs.get(i),
+            XNode::Node(_) => None,
+        }
+    }
+
+    /// The element an attribute belongs to, or the node itself.
+    fn owner(self) -> NodeRef<'a, Node> {
+        match self {
+            XNode::Node(n) | XNode::Attr(n, _) => n,
+        }
+    }
+}
+
+/// A parsed XPath 1.0 expression that selects nodes.
+pub(crate) struct XPath {
+    expr: Expr,
+}
+
+impl XPath {
+    /// Parse `s`, which must evaluate to a node-set.
+    pub(crate) fn parse(s: &str) -> Result<Self, SelectorError<'_>> {
+        let expr = xpath_parser::parse(s)?;
+        if !expr.is_node_set() {
+            return Err(SelectorError::new(
+                "XPath expression must select nodes",
+                s.trim(),
+            ));
+        }
+        Ok(XPath { expr })
+    }
+
+    /// The nodes selected from `context`, in document order.
+    pub(crate) fn select<'a>(&self, html: &'a Html, context: XNode<'a>) -> Vec<XNode<'a>> {
+        let evaluator = Evaluator {
+            html,
+            order: OnceCell::new(),
+        };
+        let ctx = Context {
+            node: context,
+            position: 1,
+            size: 1,
+        };
+        evaluator.nodes(&self.expr, &ctx)
+    }
+}
+
+/// The result of evaluating an expression.
+enum Value<'a> {
+    Nodes(Vec<XNode<'a>>),
+    Bool(bool),
+    Number(f64),
+    Str(String),
+}
+
+impl Value<'_> {
+    fn to_bool(&self) -> bool {
+        match self {
+            Value::Nodes(nodes) => !nodes.is_empty(),
+            Value::Bool(b) => *b,
+            Value::Number(n) => *n != 0.0 && !n.is_nan(),
+            Value::Str(s) => !s.is_empty(),
+        }
+    }
+
+    fn to_number(&self) -> f64 {
+        match self {
+            Value::Bool(b) => f64::from(u8::from(*b)),
+            Value::Number(n) => *n,
+            _ => str_to_number(&self.to_str()),
+        }
+    }
+
+    /// The string value; a node-set's is that of its first node in document order.
+    fn to_str(&self) -> String {
+        match self {
+            Value::Nodes(nodes) => nodes.first().map_or_else(String::new, |&n| string_value(n)),
+            Value::Bool(b) => b.to_string(),
+            Value::Number(n) => number_to_string(*n),
+            Value::Str(s) => s.clone(),
+        }
+    }
+}
+
+struct Context<'a> {
+    node: XNode<'a>,
+    /// 1-based proximity position.
+    position: usize,
+    size: usize,
+}
+
+struct Evaluator<'a> {
+    html: &'a Html,
+    /// Preorder index of every node reachable from the root, built on first sort.
+    order: OnceCell<HashMap<NodeId, usize>>,
+}
+
+impl<'a> Evaluator<'a> {
+    fn eval(&self, expr: &Expr, ctx: &Context<'a>) -> Value<'a> {
+        match expr {
+            Expr::Or(a, b) => {
+                Value::Bool(self.eval(a, ctx).to_bool() || self.eval(b, ctx).to_bool())
+            }
+            Expr::And(a, b) => {
+                Value::Bool(self.eval(a, ctx).to_bool() && self.eval(b, ctx).to_bool())
+            }
+            Expr::Compare(op, a, b) => {
+                Value::Bool(compare(*op, self.eval(a, ctx), self.eval(b, ctx)))
+            }
+            Expr::Arith(op, a, b) => {
+                let (x, y) = (self.eval(a, ctx).to_number(), self.eval(b, ctx).to_number());
+                Value::Number(match op {
+                    ArithOp::Add => x + y,
+                    ArithOp::Sub => x - y,
+                    ArithOp::Mul => x * y,
+                    ArithOp::Div => x / y,
+                    ArithOp::Mod => x % y,
+                })
+            }
+            Expr::Negate(a) => Value::Number(-self.eval(a, ctx).to_number()),
+            Expr::Union(a, b) => {
+                let mut nodes = self.nodes(a, ctx);
+                nodes.extend(self.nodes(b, ctx));
+                self.sort(&mut nodes);
+                Value::Nodes(nodes)
+            }
+            Expr::Path(path) => {
+                let start = if path.absolute {
+                    XNode::Node(self.html.tree.root())
+                } else {
+                    ctx.node
+                };
+                Value::Nodes(self.steps(vec![start], &path.steps))
+            }
+            Expr::Filter {
+                primary,
+                predicates,
+                steps,
+            } => {
+                let mut nodes = self.nodes(primary, ctx);
+                for predicate in predicates {
+                    nodes = self.filter(nodes, predicate);
+                }
+                Value::Nodes(self.steps(nodes, steps))
+            }
+            Expr::Literal(s) => Value::Str(s.clone()),
+            Expr::Number(n) => Value::Number(*n),
+            Expr::Call(function, args) => self.call(*function, args, ctx),
+        }
+    }
+
+    /// Evaluate an expression the parser has checked is a node-set.
+    fn nodes(&self, expr: &Expr, ctx: &Context<'a>) -> Vec<XNode<'a>> {
+        match self.eval(expr, ctx) {
+            Value::Nodes(nodes) => nodes,
+            _ => Vec::new(),
+        }
+    }
+
+    /// Sort into document order and drop duplicates.
+    fn sort(&self, nodes: &mut Vec<XNode<'a>>) {
+        let order = self.order.get_or_init(|| document_order(self.html));
+        // Nodes detached from the tree sort last.
+        let index = |n: NodeRef<Node>| order.get(&n.id()).copied().unwrap_or(usize::MAX);
+        nodes.sort_by_key(|node| match *node {
+            XNode::Node(n) => (index(n), 0),
+            XNode::Attr(el, i) => (index(el), i + 1),
+        });
+        nodes.dedup();
+    }
+
+    fn steps(&self, mut nodes: Vec<XNode<'a>>, steps: &[Step]) -> Vec<XNode<'a>> {
+        for step in steps {
+            let mut next = Vec::new();
+            for &node in &nodes {
+                let mut selected: Vec<XNode<'a>> = axis_nodes(node, step.axis)
+                    .into_iter()
+                    .filter(|&n| matches_test(n, step.axis, &step.test))
+                    .collect();
+                for predicate in &step.predicates {
+                    selected = self.filter(selected, predicate);
+                }
+                next.extend(selected);
+            }
+            // One context node on a forward axis already gives document order.
+            if nodes.len() > 1 || step.axis.is_reverse() {
+                self.sort(&mut next);
+            }
+            nodes = next;
+        }
+        nodes
+    }
+
+    /// Keep the nodes a predicate accepts; a number accepts the node at that position.
+    fn filter(&self, nodes: Vec<XNode<'a>>, predicate: &Expr) -> Vec<XNode<'a>> {
+        let size = nodes.len();
+        nodes
+            .into_iter()
+            .enumerate()
+            .filter(|&(i, node)| {
+                let ctx = Context {
+                    node,
+                    position: i + 1,
+                    size,
+                };
+                match self.eval(predicate, &ctx) {
+                    Value::Number(n) => n == (i + 1) as f64,
+                    value => value.to_bool(),
+                }
+            })
+            .map(|(_, node)| node)
+            .collect()
+    }
+
+    fn call(&self, function: Function, args: &[Expr], ctx: &Context<'a>) -> Value<'a> {
+        let string = |i: usize| self.eval(&args[i], ctx).to_str();
+        let number = |i: usize| self.eval(&args[i], ctx).to_number();
+        // The argument, or the context node when it is left out.
+        let string_or_context = || match args.first() {
+            Some(_) => string(0),
+            None => string_value(ctx.node),
+        };
+        let name_of = |f: fn(&QualName) -> String| {
+            let node = match args.first() {
+                Some(arg) => self.nodes(arg, ctx).first().copied(),
+                None => Some(ctx.node),
+            };
+            Value::Str(node.and_then(node_name).map_or_else(String::new, f))
+        };
+        match function {
+            Function::Last => Value::Number(ctx.size as f64),
+            Function::Position => Value::Number(ctx.position as f64),
+            Function::Count => Value::Number(self.nodes(&args[0], ctx).len() as f64),
+            Function::Id => Value::Nodes(self.id(self.eval(&args[0], ctx))),
+            Function::LocalName => name_of(|q| q.local.to_string()),
+            Function::NamespaceUri => name_of(|q| q.ns.to_string()),
+            Function::Name => name_of(qualified_name),
+            Function::String => Value::Str(string_or_context()),
+            Function::Concat => Value::Str((0..args.len()).map(string).collect()),
+            Function::StartsWith => Value::Bool(string(0).starts_with(&string(1))),
+            Function::Contains => Value::Bool(string(0).contains(&string(1))),
+            Function::SubstringBefore => {
+                let (s, t) = (string(0), string(1));
+                Value::Str(s.find(&t).map_or_else(String::new, |i| s[..i].to_string()))
+            }
+            Function::SubstringAfter => {
+                let (s, t) = (string(0), string(1));
+                Value::Str(
+                    s.find(&t)
+                        .map_or_else(String::new, |i| s[i + t.len()..].to_string()),
+                )
+            }
+            Function::Substring => Value::Str(substring(
+                &string(0),
+                number(1),
+                args.get(2).map(|_| number(2)),
+            )),
+            Function::StringLength => Value::Number(string_or_context().chars().count() as f64),
+            Function::NormalizeSpace => Value::Str(
+                string_or_context()
+                    .split(is_xpath_space)
+                    .filter(|word| !word.is_empty())
+                    .collect::<Vec<&str>>()
+                    .join(" "),
+            ),
+            Function::Translate => {
+                let from: Vec<char> = string(1).chars().collect();
+                let to: Vec<char> = string(2).chars().collect();
+                Value::Str(
+                    string(0)
+                        .chars()
+                        .filter_map(|c| match from.iter().position(|&f| f == c) {
+                            Some(i) => to.get(i).copied(),
+                            None => Some(c),
+                        })
+                        .collect(),
+                )
+            }
+            Function::Boolean => Value::Bool(self.eval(&args[0], ctx).to_bool()),
+            Function::Not => Value::Bool(!self.eval(&args[0], ctx).to_bool()),
+            Function::True => Value::Bool(true),
+            Function::False => Value::Bool(false),
+            Function::Lang => Value::Bool(lang_matches(ctx.node, &string(0))),
+            Function::Number => Value::Number(match args.first() {
+                Some(_) => number(0),
+                None => str_to_number(&string_value(ctx.node)),
+            }),
+            Function::Sum => Value::Number(
+                self.nodes(&args[0], ctx)
+                    .into_iter()
+                    .map(|n| str_to_number(&string_value(n)))
+                    .sum(),
+            ),
+            Function::Floor => Value::Number(number(0).floor()),
+            Function::Ceiling => Value::Number(number(0).ceil()),
+            Function::Round => Value::Number(round(number(0))),
+        }
+    }
+
+    /// Elements with any of the whitespace-separated IDs in `value`, first match per ID.
+    fn id(&self, value: Value<'a>) -> Vec<XNode<'a>> {
+        let ids: Vec<String> = match value {
+            Value::Nodes(nodes) => nodes.into_iter().map(string_value).collect(),
+            value => vec![value.to_str()],
+        };
+        let mut wanted: HashSet<&str> = ids
+            .iter()
+            .flat_map(|s| s.split(is_xpath_space))
+            .filter(|id| !id.is_empty())
+            .collect();
+        let mut found = Vec::new();
+        for node in self.html.tree.root().descendants() {
+            if wanted.is_empty() {
+                break;
+            }
+            let id = node.value().as_element().and_then(|el| el.id());
+            if id.is_some_and(|id| wanted.remove(id)) {
+                found.push(XNode::Node(node));
+            }
+        }
+        found
+    }
+}
+
+/// Doctypes are not part of the XPath data model.
+fn tree_nodes<'a>(nodes: impl Iterator<Item = NodeRef<'a, Node>>) -> Vec<XNode<'a>> {
+    nodes
+        .filter(|n| !n.value().is_doctype())
+        .map(XNode::Node)
+        .collect()
+}
+
+/// Nodes after `n` in document order that are not its descendants.
+fn following<'a>(n: NodeRef<'a, Node>) -> impl Iterator<Item = NodeRef<'a, Node>> {
+    iter::once(n)
+        .chain(n.ancestors())
+        .flat_map(|a| a.next_siblings())
+        .flat_map(|s| s.descendants())
+}
+
+/// Nodes before `n` that are not its ancestors, in reverse document order.
+fn preceding<'a>(n: NodeRef<'a, Node>) -> impl Iterator<Item = NodeRef<'a, Node>> {
+    iter::once(n)
+        .chain(n.ancestors())
+        .flat_map(|a| a.prev_siblings())
+        .flat_map(|s| s.descendants().collect::<Vec<_>>().into_iter().rev())
+}
+
+/// The nodes on `axis` from `node`, in axis order (reverse axes count backwards).
+fn axis_nodes(node: XNode<'_>, axis: Axis) -> Vec<XNode<'_>> {
+    let n = node.owner();
+    match (node, axis) {
+        (_, Axis::SelfNode) => vec![node],
+        (_, Axis::Namespace) => Vec::new(),
+        (XNode::Attr(..), Axis::DescendantOrSelf) => vec![node],
+        (XNode::Attr(..), Axis::Parent) => vec![XNode::Node(n)],
+        (XNode::Attr(..), Axis::Ancestor) => tree_nodes(iter::once(n).chain(n.ancestors())),
+        (XNode::Attr(..), Axis::AncestorOrSelf) => {
+            let mut nodes = vec![node];
+            nodes.extend(tree_nodes(iter::once(n).chain(n.ancestors())));
+            nodes
+        }
+        // An element's descendants follow its attributes.
+        (XNode::Attr(..), Axis::Following) => {
+            tree_nodes(n.descendants().skip(1).chain(following(n)))
+        }
+        (XNode::Attr(..), Axis::Preceding) => tree_nodes(preceding(n)),
+        (XNode::Attr(..), _) => Vec::new(),
+        (XNode::Node(_), Axis::Child) => tree_nodes(n.children()),
+        (XNode::Node(_), Axis::Descendant) => tree_nodes(n.descendants().skip(1)),
+        (XNode::Node(_), Axis::DescendantOrSelf) => tree_nodes(n.descendants()),
+        (XNode::Node(_), Axis::Parent) => tree_nodes(n.parent().into_iter()),
+        (XNode::Node(_), Axis::Ancestor) => tree_nodes(n.ancestors()),
+        (XNode::Node(_), Axis::AncestorOrSelf) => tree_nodes(iter::once(n).chain(n.ancestors())),
+        (XNode::Node(_), Axis::FollowingSibling) => tree_nodes(n.next_siblings()),
+        (XNode::Node(_), Axis::PrecedingSibling) => tree_nodes(n.prev_siblings()),
+        (XNode::Node(_), Axis::Following) => tree_nodes(following(n)),
+        (XNode::Node(_), Axis::Preceding) => tree_nodes(preceding(n)),
+        (XNode::Node(_), Axis::Attribute) => match n.value().as_element() {
+            Some(el) => (0..el.attrs.len()).map(|i| XNode::Attr(n, i)).collect(),
+            None => Vec::new(),
+        },
+    }
+}
+
+/// The name of an element or attribute node.
+fn node_name(node: XNode<'_>) -> Option<&QualName> {
+    match node {
+        XNode::Node(n) => n.value().as_element().map(|el| &el.name),
+        XNode::Attr(..) => node.attr().map(|(name, _)| name),
+    }
+}
+
+fn qualified_name(name: &QualName) -> String {
+    match &name.prefix {
+        Some(prefix) => format!("{prefix}:{}", name.local),
+        None => name.local.to_string(),
+    }
+}
+
+fn matches_test(node: XNode<'_>, axis: Axis, test: &NodeTest) -> bool {
+    let value = match node {
+        XNode::Node(n) => Some(n.value()),
+        XNode::Attr(..) => None,
+    };
+    match test {
+        NodeTest::Node => true,
+        NodeTest::Text => value.is_some_and(Node::is_text),
+        NodeTest::Comment => value.is_some_and(Node::is_comment),
+        NodeTest::ProcessingInstruction(target) => value
+            .and_then(Node::as_processing_instruction)
+            .is_some_and(|pi| target.as_ref().is_none_or(|t| **t == *pi.target)),
+        NodeTest::AnyName { .. } | NodeTest::Name(_) => {
+            // Only the axis's principal node type has a name to test.
+            let principal = match node {
+                XNode::Attr(..) => axis == Axis::Attribute,
+                XNode::Node(_) => axis != Axis::Attribute,
+            };
+            let Some(name) = node_name(node).filter(|_| principal) else {
+                return false;
+            };
+            match test {
+                NodeTest::AnyName { prefix: None } => true,
+                NodeTest::AnyName {
+                    prefix: Some(prefix),
+                } => name.prefix.as_deref() == Some(prefix.as_str()),
+                NodeTest::Name(test) => match (&name.prefix, test.split_once(':')) {
+                    (Some(prefix), Some((p, local))) => **prefix == *p && *name.local == *local,
+                    (None, None) => *name.local == **test,
+                    _ => false,
+                },
+                _ => false,
+            }
+        }
+    }
+}
+
+fn string_value(node: XNode<'_>) -> String {
+    if let Some((_, value)) = node.attr() {
+        return value.to_string();
+    }
+    let n = node.owner();
+    match n.value() {
+        Node::Text(t) => t.text.to_string(),
+        Node::Comment(c) => c.comment.to_string(),
+        Node::ProcessingInstruction(pi) => pi.data.to_string(),
+        Node::Element(_) | Node::Document | Node::Fragment => n
+            .descendants()
+            .filter_map(|d| d.value().as_text())
+            .map(|t| &*t.text)
+            .collect(),
+        Node::Doctype(_) => String::new(),
+    }
+}
+
+fn str_to_number(s: &str) -> f64 {
+    let s = s.trim_matches(is_xpath_space);
+    let digits = s.strip_prefix('-').unwrap_or(s);
+    let valid = digits.chars().all(|c| c.is_ascii_digit() || c == '.')
+        && digits.matches('.').count() <= 1
+        && digits.chars().any(|c| c.is_ascii_digit());
+    if valid {
+        s.parse().unwrap_or(f64::NAN)
+    } else {
+        f64::NAN
+    }
+}
+
+fn number_to_string(n: f64) -> String {
+    if n.is_nan() {
+        "NaN".to_string()
+    } else if n.is_infinite() {
+        if n > 0.0 { "Infinity" } else { "-Infinity" }.to_string()
+    } else if n == 0.0 {
+        "0".to_string()
+    } else {
+        // Rust never uses an exponent here, matching XPath's number-to-string.
+        n.to_string()
+    }
+}
+
+/// XPath `round`: halves round up, and -0.5 up to 0 round to negative zero.
+fn round(n: f64) -> f64 {
+    if !n.is_finite() {
+        n
+    } else if (-0.5..0.0).contains(&n) {
+        -0.0
+    } else {
+        (n + 0.5).floor()
+    }
+}
+
+/// XPath `substring`: 1-based, with positions and length rounded.
+fn substring(s: &str, start: f64, len: Option<f64>) -> String {
+    let start = round(start);
+    let end = len.map_or(f64::INFINITY, |len| start + round(len));
+    s.chars()
+        .enumerate()
+        .filter(|&(i, _)| {
+            let position = (i + 1) as f64;
+            position >= start && position < end
+        })
+        .map(|(_, c)| c)
+        .collect()
+}
+
+/// Whether the nearest `lang` attribute names `lang` or a sublanguage of it.
+fn lang_matches(node: XNode<'_>, lang: &str) -> bool {
+    let n = node.owner();
+    iter::once(n)
+        .chain(n.ancestors())
+        .find_map(|a| {
+            a.value()
+                .as_element()?
+                .attrs
+                .iter()
+                .find(|(name, _)| &*name.local == "lang")
+                .map(|(_, value)| value.to_ascii_lowercase())
+        })
+        .is_some_and(|value| {
+            let lang = lang.to_ascii_lowercase();
+            value == lang
+                || value
+                    .strip_prefix(&lang)
+                    .is_some_and(|sub| sub.starts_with('-'))
+        })
+}
+
+/// Compare two values by the XPath 1.0 rules, where a node-set compares true if any
+/// of its nodes does.
+fn compare(op: CompareOp, a: Value<'_>, b: Value<'_>) -> bool {
+    let strings = |nodes: Vec<XNode>| -> Vec<Value> {
+        nodes
+            .into_iter()
+            .map(|n| Value::Str(string_value(n)))
+            .collect()
+    };
+    match (a, b) {
+        (a @ Value::Nodes(_), b @ Value::Bool(_)) | (a @ Value::Bool(_), b @ Value::Nodes(_)) => {
+            compare_atoms(op, &Value::Bool(a.to_bool()), &Value::Bool(b.to_bool()))
+        }
+        (Value::Nodes(x), Value::Nodes(y)) => {
+            let ys = strings(y);
+            strings(x)
+                .iter()
+                .any(|x| ys.iter().any(|y| compare_atoms(op, x, y)))
+        }
+        (Value::Nodes(x), b) => strings(x).iter().any(|x| compare_atoms(op, x, &b)),
+        (a, Value::Nodes(y)) => strings(y).iter().any(|y| compare_atoms(op, &a, y)),
+        (a, b) => compare_atoms(op, &a, &b),
+    }
+}
+
+fn compare_atoms(op: CompareOp, a: &Value, b: &Value) -> bool {
+    let equal = || match (a, b) {
+        (Value::Bool(_), _) | (_, Value::Bool(_)) => a.to_bool() == b.to_bool(),
+        (Value::Number(_), _) | (_, Value::Number(_)) => a.to_number() == b.to_number(),
+        _ => a.to_str() == b.to_str(),
+    };
+    match op {
+        CompareOp::Eq => equal(),
+        CompareOp::Ne => !equal(),
+        CompareOp::Lt => a.to_number() < b.to_number(),
+        CompareOp::Le => a.to_number() <= b.to_number(),
+        CompareOp::Gt => a.to_number() > b.to_number(),
+        CompareOp::Ge => a.to_number() >= b.to_number(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HTML: &str = r#"<div id="a"><p id="p1">one <b id="b1">bold</b></p><p id="p2" lang="en-US">  two   words </p><p id="p3">three</p></div><ul id="u"><li id="l1">x</li><li id="l2">y</li><li id="l3">z</li></ul>"#;
+
+    /// What `expr` selects from the document root: elements by id (or tag name),
+    /// attributes and text by value.
+    fn select(expr: &str) -> Vec<String> {
+        let html = Html::parse_document(HTML);
+        let xpath = XPath::parse(expr).ok().unwrap();
+        xpath
+            .select(&html, XNode::Node(html.tree.root()))
+            .into_iter()
+            .map(|node| match node.owner().value() {
+                Node::Element(el) if node.attr().is_none() => {
+                    el.id().unwrap_or(&el.name.local).to_string()
+                }
+                _ => string_value(node),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn axes() {
+        assert_eq!(select("//b/ancestor::*"), ["html", "body", "a", "p1"]);
+        assert_eq!(select("//b/ancestor-or-self::p"), ["p1"]);
+        assert_eq!(select("//li[2]/parent::ul"), ["u"]);
+        assert_eq!(select("//p[1]/descendant-or-self::*"), ["p1", "b1"]);
+        assert_eq!(select("//div/descendant::b"), ["b1"]);
+        assert_eq!(select("//p[2]/following-sibling::p"), ["p3"]);
+        assert_eq!(select("//p[3]/preceding-sibling::p"), ["p1", "p2"]);
+        assert_eq!(select("//p[2]/preceding::*"), ["head", "p1", "b1"]);
+        assert_eq!(select("//p[3]/following::li"), ["l1", "l2", "l3"]);
+        assert_eq!(select("//li/self::li[@id='l2']"), ["l2"]);
+        assert_eq!(select("//p/@lang"), ["en-US"]);
+        assert_eq!(select("//p[1]/text()"), ["one "]);
+        assert_eq!(select("//p[1]/node()"), ["one ", "b1"]);
+    }
+
+    #[test]
+    fn positional_predicates() {
+        assert_eq!(select("//li[2]"), ["l2"]);
+        assert_eq!(select("//li[last()]"), ["l3"]);
+        assert_eq!(select("//li[position() > 1]"), ["l2", "l3"]);
+        assert_eq!(select("(//li)[1]"), ["l1"]);
+        assert_eq!(select("(//p | //li)[last()]"), ["l3"]);
+        assert_eq!(select("//li[position() > 1][1]"), ["l2"]);
+        // Reverse axes count back from the context node.
+        assert_eq!(select("//li[3]/preceding-sibling::li[1]"), ["l2"]);
+        assert_eq!(select("//b/ancestor::*[1]"), ["p1"]);
+        assert_eq!(select("//b/ancestor::*[last()]"), ["html"]);
+    }
+
+    #[test]
+    fn string_functions() {
+        assert_eq!(select("//p[string() = 'one bold']"), ["p1"]);
+        assert_eq!(select("//p[string(b) = 'bold']"), ["p1"]);
+        assert_eq!(select("//p[normalize-space() = 'two words']"), ["p2"]);
+        assert_eq!(select("//p[normalize-space(.) = 'three']"), ["p3"]);
+        assert_eq!(select("//p[string-length(normalize-space()) = 9]"), ["p2"]);
+        assert_eq!(select("//p[. = 'three']"), ["p3"]);
+        assert_eq!(
+            select("//*[starts-with(@id, 'l')][contains(., 'z')]"),
+            ["l3"]
+        );
+    }
+
+    #[test]
+    fn unions_are_in_document_order() {
+        assert_eq!(select("//li[3] | //p[1]"), ["p1", "l3"]);
+        assert_eq!(select("//b | //div"), ["a", "b1"]);
+        assert_eq!(select("//li | //li[1]"), ["l1", "l2", "l3"]);
+        assert_eq!(select("//p[2]/@lang | //p[2]"), ["p2", "en-US"]);
+    }
+
+    #[test]
+    fn rejects_non_node_sets() {
+        assert!(XPath::parse("count(//p)").is_err());
+        assert!(XPath::parse("//p[").is_err());
+    }
+}
@@ -0,0 +1,815 @@
+use crate::selector_error::SelectorError;
+
+/// A parsed XPath 1.0 expression.
+pub(crate) enum Expr {
+    Or(Box<Expr>, Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Compare(CompareOp, Box<Expr>, Box<Expr>),
+    Arith(ArithOp, Box<Expr>, Box<Expr>),
+    Negate(Box<Expr>),
+    Union(Box<Expr>, Box<Expr>),
+    Path(LocationPath),
+    /// `primary[predicate]…/steps…`; `primary` always yields a node-set.
+    Filter {
+        primary: Box<Expr>,
+        predicates: Vec<Expr>,
+        steps: Vec<Step>,
+    },
+    Literal(String),
+    Number(f64),
+    Call(Function, Vec<Expr>),
+}
+
+#[derive(Clone, Copy)]
+pub(crate) enum CompareOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Clone, Copy)]
+pub(crate) enum ArithOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+}
+
+pub(crate) struct LocationPath {
+    pub absolute: bool,
+    pub steps: Vec<Step>,
+}
+
+pub(crate) struct Step {
+    pub axis: Axis,
+    pub test: NodeTest,
+    pub predicates: Vec<Expr>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Axis {
+    Ancestor,
+    AncestorOrSelf,
+    Attribute,
+    Child,
+    Descendant,
+    DescendantOrSelf,
+    Following,
+    FollowingSibling,
+    Namespace,
+    Parent,
+    Preceding,
+    PrecedingSibling,
+    SelfNode,
+}
+
+const AXES: &[(&str, Axis)] = &[
+    ("ancestor", Axis::Ancestor),
+    ("ancestor-or-self", Axis::AncestorOrSelf),
+    ("attribute", Axis::Attribute),
+    ("child", Axis::Child),
+    ("descendant", Axis::Descendant),
+    ("descendant-or-self", Axis::DescendantOrSelf),
+    ("following", Axis::Following),
+    ("following-sibling", Axis::FollowingSibling),
+    ("namespace", Axis::Namespace),
+    ("parent", Axis::Parent),
+    ("preceding", Axis::Preceding),
+    ("preceding-sibling", Axis::PrecedingSibling),
+    ("self", Axis::SelfNode),
+];
+
+impl Axis {
+    /// Whether proximity positions count backwards from the context node.
+    pub(crate) fn is_reverse(self) -> bool {
+        matches!(
+            self,
+            Axis::Ancestor | Axis::AncestorOrSelf | Axis::Preceding | Axis::PrecedingSibling
+        )
+    }
+}
+
+pub(crate) enum NodeTest {
+    /// `*` or `prefix:*`.
+    AnyName {
+        prefix: Option<String>,
+    },
+    /// A name as written, prefix included. Namespaces are not resolved, as in Jsoup.
+    Name(String),
+    Node,
+    Text,
+    Comment,
+    ProcessingInstruction(Option<String>),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Function {
+    Last,
+    Position,
+    Count,
+    Id,
+    LocalName,
+    NamespaceUri,
+    Name,
+    String,
+    Concat,
+    StartsWith,
+    Contains,
+    SubstringBefore,
+    SubstringAfter,
+    Substring,
+    StringLength,
+    NormalizeSpace,
+    Translate,
+    Boolean,
+    Not,
+    True,
+    False,
+    Lang,
+    Number,
+    Sum,
+    Floor,
+    Ceiling,
+    Round,
+}
+
+/// Core function library: name, function, fewest and most arguments.
+const FUNCTIONS: &[(&str, Function, usize, usize)] = &[
+    ("last", Function::Last, 0, 0),
+    ("position", Function::Position, 0, 0),
+    ("count", Function::Count, 1, 1),
+    ("id", Function::Id, 1, 1),
+    ("local-name", Function::LocalName, 0, 1),
+    ("namespace-uri", Function::NamespaceUri, 0, 1),
+    ("name", Function::Name, 0, 1),
+    ("string", Function::String, 0, 1),
+    ("concat", Function::Concat, 2, usize::MAX),
+    ("starts-with", Function::StartsWith, 2, 2),
+    ("contains", Function::Contains, 2, 2),
+    ("substring-before", Function::SubstringBefore, 2, 2),
+    ("substring-after", Function::SubstringAfter, 2, 2),
+    ("substring", Function::Substring, 2, 3),
+    ("string-length", Function::StringLength, 0, 1),
+    ("normalize-space", Function::NormalizeSpace, 0, 1),
+    ("translate", Function::Translate, 3, 3),
+    ("boolean", Function::Boolean, 1, 1),
+    ("not", Function::Not, 1, 1),
+    ("true", Function::True, 0, 0),
+    ("false", Function::False, 0, 0),
+    ("lang", Function::Lang, 1, 1),
+    ("number", Function::Number, 0, 1),
+    ("sum", Function::Sum, 1, 1),
+    ("floor", Function::Floor, 1, 1),
+    ("ceiling", Function::Ceiling, 1, 1),
+    ("round", Function::Round, 1, 1),
+];
+
+impl Function {
+    /// Whether the arguments must be node-sets.
+    fn takes_node_set(self) -> bool {
+        matches!(
+            self,
+            Function::Count
+                | Function::Sum
+                | Function::LocalName
+                | Function::NamespaceUri
+                | Function::Name
+        )
+    }
+}
+
+impl Expr {
+    /// Whether the expression always evaluates to a node-set. XPath 1.0 has no other
+    /// way to get one, so this can be checked before evaluating anything.
+    pub(crate) fn is_node_set(&self) -> bool {
+        matches!(
+            self,
+            Expr::Union(..) | Expr::Path(_) | Expr::Filter { .. } | Expr::Call(Function::Id, _)
+        )
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Token<'a> {
+    LParen,
+    RParen,
+    LBracket,
+    RBracket,
+    Dot,
+    DotDot,
+    At,
+    Comma,
+    ColonColon,
+    Slash,
+    DoubleSlash,
+    Pipe,
+    Plus,
+    Minus,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Multiply,
+    And,
+    Or,
+    Mod,
+    Div,
+    NameTest {
+        prefix: Option<&'a str>,
+        /// `None` for `*`.
+        local: Option<&'a str>,
+    },
+    NodeType(&'a str),
+    FunctionName(&'a str),
+    AxisName(&'a str),
+    Literal(&'a str),
+    Number(f64),
+    Variable(&'a str),
+}
+
+impl Token<'_> {
+    /// Whether a name or `*` after this token is a name test rather than an operator.
+    fn allows_name(self) -> bool {
+        matches!(
+            self,
+            Token::At
+                | Token::ColonColon
+                | Token::LParen
+                | Token::LBracket
+                | Token::Comma
+                | Token::Slash
+                | Token::DoubleSlash
+                | Token::Pipe
+                | Token::Plus
+                | Token::Minus
+                | Token::Eq
+                | Token::Ne
+                | Token::Lt
+                | Token::Le
+                | Token::Gt
+                | Token::Ge
+                | Token::Multiply
+                | Token::And
+                | Token::Or
+                | Token::Mod
+                | Token::Div
+        )
+    }
+}
+
+const NODE_TYPES: &[&str] = &["comment", "text", "processing-instruction", "node"];
+
+pub(crate) fn is_xpath_space(c: char) -> bool {
+    matches!(c, ' ' | '\t' | '\r' | '\n')
+}
+
+fn is_name_start(c: char) -> bool {
+    c.is_alphabetic() || c == '_' || !c.is_ascii()
+}
+
+fn ncname_len(s: &str) -> usize {
+    match s.chars().next() {
+        Some(c) if is_name_start(c) => s
+            .char_indices()
+            .find(|&(_, c)| !(c.is_alphanumeric() || matches!(c, '.' | '-' | '_') || !c.is_ascii()))
+            .map_or(s.len(), |(i, _)| i),
+        _ => 0,
+    }
+}
+
+fn digits_len(s: &str) -> usize {
+    s.bytes().take_while(u8::is_ascii_digit).count()
+}
+
+/// Split an expression into tokens, each with the slice it was read from.
+fn lex(s: &str) -> Result<Vec<(Token<'_>, &str)>, SelectorError<'_>> {
+    let mut tokens: Vec<(Token, &str)> = Vec::new();
+    let mut i = 0;
+    loop {
+        i += s[i..].len() - s[i..].trim_start_matches(is_xpath_space).len();
+        let rest = &s[i..];
+        let Some(c) = rest.chars().next() else {
+            return Ok(tokens);
+        };
+        let name_allowed = tokens.last().is_none_or(|(t, _)| t.allows_name());
+        let two = |token| Ok((token, 2));
+        let (token, len) = match c {
+            '(' => Ok((Token::LParen, 1)),
+            ')' => Ok((Token::RParen, 1)),
+            '[' => Ok((Token::LBracket, 1)),
+            ']' => Ok((Token::RBracket, 1)),
+            '@' => Ok((Token::At, 1)),
+            ',' => Ok((Token::Comma, 1)),
+            '|' => Ok((Token::Pipe, 1)),
+            '+' => Ok((Token::Plus, 1)),
+            '-' => Ok((Token::Minus, 1)),
+            '=' => Ok((Token::Eq, 1)),
+            '!' if rest.starts_with("!=") => two(Token::Ne),
+            '<' if rest.starts_with("<=") => two(Token::Le),
+            '<' => Ok((Token::Lt, 1)),
+            '>' if rest.starts_with(">=") => two(Token::Ge),
+            '>' => Ok((Token::Gt, 1)),
+            '/' if rest.starts_with("//") => two(Token::DoubleSlash),
+            '/' => Ok((Token::Slash, 1)),
+            ':' if rest.starts_with("::") => two(Token::ColonColon),
+            '.' if rest.starts_with("..") => two(Token::DotDot),
+            '.' | '0'..='9' => {
+                let int = digits_len(rest);
+                let len = if rest[int..].starts_with('.') {
+                    int + 1 + digits_len(&rest[int + 1..])
+                } else {
+                    int
+                };
+                if len == 1 && c == '.' {
+                    Ok((Token::Dot, 1))
+                } else {
+                    Ok((Token::Number(rest[..len].parse().unwrap_or(f64::NAN)), len))
+                }
+            }
+            '"' | '\'' => match rest[1..].find(c) {
+                Some(end) => Ok((Token::Literal(&rest[1..=end]), end + 2)),
+                None => Err(SelectorError::new("Unterminated string literal", rest)),
+            },
+            '*' if name_allowed => Ok((
+                Token::NameTest {
+                    prefix: None,
+                    local: None,
+                },
+                1,
+            )),
+            '*' => Ok((Token::Multiply, 1)),
+            '$' => match ncname_len(&rest[1..]) {
+                0 => Err(SelectorError::new("Expected a variable name", &rest[..1])),
+                len => Ok((Token::Variable(&rest[1..=len]), len + 1)),
+            },
+            c if is_name_start(c) => lex_name(rest, name_allowed),
+            _ => Err(SelectorError::new(
+                format!("Unexpected character '{c}'"),
+                &rest[..c.len_utf8()],
+            )),
+        }?;
+        tokens.push((token, &rest[..len]));
+        i += len;
+    }
+}
+
+/// Read a name at the start of `rest`, classified by what follows it.
+fn lex_name(rest: &str, name_allowed: bool) -> Result<(Token<'_>, usize), SelectorError<'_>> {
+    let first = ncname_len(rest);
+    let name = &rest[..first];
+    if !name_allowed {
+        let operator = match name {
+            "and" => Token::And,
+            "or" => Token::Or,
+            "mod" => Token::Mod,
+            "div" => Token::Div,
+            _ => {
+                return Err(SelectorError::new(
+                    format!("Expected an operator but found '{name}'"),
+                    name,
+                ))
+            }
+        };
+        return Ok((operator, first));
+    }
+
+    // prefix:local or prefix:*, but not axis::
+    let after = &rest[first..];
+    let (prefix, local, len) = if after.starts_with(':') && !after.starts_with("::") {
+        if after[1..].starts_with('*') {
+            (Some(name), None, first + 2)
+        } else {
+            match ncname_len(&after[1..]) {
+                0 => (None, Some(name), first),
+                n => (Some(name), Some(&after[1..=n]), first + 1 + n),
+            }
+        }
+    } else {
+        (None, Some(name), first)
+    };
+
+    let following = rest[len..].trim_start_matches(is_xpath_space);
+    let token = match (prefix, local) {
+        (None, Some(name)) if following.starts_with("::") => Token::AxisName(name),
+        (None, Some(name)) if following.starts_with('(') && NODE_TYPES.contains(&name) => {
+            Token::NodeType(name)
+        }
+        (_, Some(_)) if following.starts_with('(') => Token::FunctionName(&rest[..len]),
+        (prefix, local) => Token::NameTest { prefix, local },
+    };
+    Ok((token, len))
+}
+
+struct Parser<'a> {
+    source: &'a str,
+    tokens: Vec<(Token<'a>, &'a str)>,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<Token<'a>> {
+        self.tokens.get(self.pos).map(|&(t, _)| t)
+    }
+
+    /// The slice of the current token, or the empty end of the source.
+    fn here(&self) -> &'a str {
+        self.tokens
+            .get(self.pos)
+            .map_or(&self.source[self.source.len()..], |&(_, raw)| raw)
+    }
+
+    fn eat(&mut self, token: Token) -> bool {
+        if self.peek() == Some(token) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, token: Token, what: &str) -> Result<(), SelectorError<'a>> {
+        if self.eat(token) {
+            Ok(())
+        } else {
+            Err(self.unexpected(what))
+        }
+    }
+
+    fn unexpected(&self, expected: &str) -> SelectorError<'a> {
+        let found = self.here();
+        if found.is_empty() {
+            SelectorError::new(format!("Expected {expected} at end of expression"), found)
+        } else {
+            SelectorError::new(format!("Expected {expected} but found '{found}'"), found)
+        }
+    }
+
+    /// Parse an operand that must be a node-set, starting at the current token.
+    fn node_set(&mut self, parse: fn(&mut Self) -> PResult<'a>) -> PResult<'a> {
+        let start = self.here();
+        let expr = parse(self)?;
+        if expr.is_node_set() {
+            Ok(expr)
+        } else {
+            Err(SelectorError::new("Expression must be a node-set", start))
+        }
+    }
+
+    fn binary(
+        &mut self,
+        next: fn(&mut Self) -> PResult<'a>,
+        op: fn(Token) -> Option<MakeBinary>,
+    ) -> PResult<'a> {
+        let mut left = next(self)?;
+        while let Some(make) = self.peek().and_then(op) {
+            self.pos += 1;
+            let right = next(self)?;
+            left = make(Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn or_expr(&mut self) -> PResult<'a> {
+        self.binary(Self::and_expr, |t| match t {
+            Token::Or => Some(Expr::Or),
+            _ => None,
+        })
+    }
+
+    fn and_expr(&mut self) -> PResult<'a> {
+        self.binary(Self::equality_expr, |t| match t {
+            Token::And => Some(Expr::And),
+            _ => None,
+        })
+    }
+
+    fn equality_expr(&mut self) -> PResult<'a> {
+        self.binary(Self::relational_expr, |t| match t {
+            Token::Eq => Some(|a, b| Expr::Compare(CompareOp::Eq, a, b)),
+            Token::Ne => Some(|a, b| Expr::Compare(CompareOp::Ne, a, b)),
+            _ => None,
+        })
+    }
+
+    fn relational_expr(&mut self) -> PResult<'a> {
+        self.binary(Self::additive_expr, |t| match t {
+            Token::Lt => Some(|a, b| Expr::Compare(CompareOp::Lt, a, b)),
+            Token::Le => Some(|a, b| Expr::Compare(CompareOp::Le, a, b)),
+            Token::Gt => Some(|a, b| Expr::Compare(CompareOp::Gt, a, b)),
+            Token::Ge => Some(|a, b| Expr::Compare(CompareOp::Ge, a, b)),
+            _ => None,
+        })
+    }
+
+    fn additive_expr(&mut self) -> PResult<'a> {
+        self.binary(Self::multiplicative_expr, |t| match t {
+            Token::Plus => Some(|a, b| Expr::Arith(ArithOp::Add, a, b)),
+            Token::Minus => Some(|a, b| Expr::Arith(ArithOp::Sub, a, b)),
+            _ => None,
+        })
+    }
+
+    fn multiplicative_expr(&mut self) -> PResult<'a> {
+        self.binary(Self::unary_expr, |t| match t {
+            Token::Multiply => Some(|a, b| Expr::Arith(ArithOp::Mul, a, b)),
+            Token::Div => Some(|a, b| Expr::Arith(ArithOp::Div, a, b)),
+            Token::Mod => Some(|a, b| Expr::Arith(ArithOp::Mod, a, b)),
+            _ => None,
+        })
+    }
+
+    fn unary_expr(&mut self) -> PResult<'a> {
+        if self.eat(Token::Minus) {
+            Ok(Expr::Negate(Box::new(self.unary_expr()?)))
+        } else {
+            self.union_expr()
+        }
+    }
+
+    fn union_expr(&mut self) -> PResult<'a> {
+        let start = self.here();
+        let first = self.path_expr()?;
+        if self.peek() != Some(Token::Pipe) {
+            return Ok(first);
+        }
+        if !first.is_node_set() {
+            return Err(SelectorError::new("Expression must be a node-set", start));
+        }
+        let mut left = first;
+        while self.eat(Token::Pipe) {
+            let right = self.node_set(Self::path_expr)?;
+            left = Expr::Union(Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn starts_step(&self) -> bool {
+        matches!(
+            self.peek(),
+            Some(
+                Token::NameTest { .. }
+                    | Token::NodeType(_)
+                    | Token::AxisName(_)
+                    | Token::At
+                    | Token::Dot
+                    | Token::DotDot
+            )
+        )
+    }
+
+    fn path_expr(&mut self) -> PResult<'a> {
+        if self.eat(Token::Slash) {
+            let steps = if self.starts_step() {
+                self.relative_path(Vec::new())?
+            } else {
+                Vec::new()
+            };
+            return Ok(Expr::Path(LocationPath {
+                absolute: true,
+                steps,
+            }));
+        }
+        if self.eat(Token::DoubleSlash) {
+            let steps = self.relative_path(vec![descendant_or_self()])?;
+            return Ok(Expr::Path(LocationPath {
+                absolute: true,
+                steps,
+            }));
+        }
+        if self.starts_step() {
+            let steps = self.relative_path(Vec::new())?;
+            return Ok(Expr::Path(LocationPath {
+                absolute: false,
+                steps,
+            }));
+        }
+
+        let start = self.here();
+        let primary = self.primary_expr()?;
+        let predicates = self.predicates()?;
+        let steps = match self.peek() {
+            Some(Token::Slash) => {
+                self.pos += 1;
+                self.relative_path(Vec::new())?
+            }
+            Some(Token::DoubleSlash) => {
+                self.pos += 1;
+                self.relative_path(vec![descendant_or_self()])?
+            }
+            _ => Vec::new(),
+        };
+        if predicates.is_empty() && steps.is_empty() {
+            return Ok(primary);
+        }
+        if !primary.is_node_set() {
+            return Err(SelectorError::new("Expression must be a node-set", start));
+        }
+        Ok(Expr::Filter {
+            primary: Box::new(primary),
+            predicates,
+            steps,
+        })
+    }
+
+    fn relative_path(&mut self, mut steps: Vec<Step>) -> Result<Vec<Step>, SelectorError<'a>> {
+        loop {
+            let step = self.step()?;
+            // `//name` selects the same nodes as `descendant::name` when no predicate
+            // depends on the position among siblings, without a pass per node.
+            match steps.last_mut() {
+                Some(last)
+                    if last.axis == Axis::DescendantOrSelf
+                        && matches!(last.test, NodeTest::Node)
+                        && last.predicates.is_empty()
+                        && step.axis == Axis::Child
+                        && step.predicates.is_empty() =>
+                {
+                    *last = Step {
+                        axis: Axis::Descendant,
+                        ..step
+                    };
+                }
+                _ => steps.push(step),
+            }
+            if self.eat(Token::DoubleSlash) {
+                steps.push(descendant_or_self());
+            } else if !self.eat(Token::Slash) {
+                return Ok(steps);
+            }
+        }
+    }
+
+    fn step(&mut self) -> Result<Step, SelectorError<'a>> {
+        let abbreviated = |axis| Step {
+            axis,
+            test: NodeTest::Node,
+            predicates: Vec::new(),
+        };
+        if self.eat(Token::Dot) {
+            return Ok(abbreviated(Axis::SelfNode));
+        }
+        if self.eat(Token::DotDot) {
+            return Ok(abbreviated(Axis::Parent));
+        }
+        let axis = match self.peek() {
+            Some(Token::At) => {
+                self.pos += 1;
+                Axis::Attribute
+            }
+            Some(Token::AxisName(name)) => {
+                let raw = self.here();
+                let axis = AXES
+                    .iter()
+                    .find(|&&(axis, _)| axis == name)
+                    .map(|&(_, axis)| axis)
+                    .ok_or_else(|| SelectorError::new(format!("Unknown axis '{name}'"), raw))?;
+                self.pos += 1;
+                self.expect(Token::ColonColon, "'::'")?;
+                axis
+            }
+            _ => Axis::Child,
+        };
+        let test = self.node_test()?;
+        Ok(Step {
+            axis,
+            test,
+            predicates: self.predicates()?,
+        })
+    }
+
+    fn node_test(&mut self) -> Result<NodeTest, SelectorError<'a>> {
+        match self.peek() {
+            Some(Token::NameTest { prefix, local }) => {
+                self.pos += 1;
+                Ok(match local {
+                    None => NodeTest::AnyName {
+                        prefix: prefix.map(str::to_string),
+                    },
+                    Some(_) => NodeTest::Name(self.tokens[self.pos - 1].1.to_string()),
+                })
+            }
+            Some(Token::NodeType(name)) => {
+                self.pos += 1;
+                self.expect(Token::LParen, "'('")?;
+                let test = match name {
+                    "comment" => NodeTest::Comment,
+                    "text" => NodeTest::Text,
+                    "node" => NodeTest::Node,
+                    _ => match self.peek() {
+                        Some(Token::Literal(target)) => {
+                            self.pos += 1;
+                            NodeTest::ProcessingInstruction(Some(target.to_string()))
+                        }
+                        _ => NodeTest::ProcessingInstruction(None),
+                    },
+                };
+                self.expect(Token::RParen, "')'")?;
+                Ok(test)
+            }
+            _ => Err(self.unexpected("a node test")),
+        }
+    }
+
+    fn predicates(&mut self) -> Result<Vec<Expr>, SelectorError<'a>> {
+        let mut predicates = Vec::new();
+        while self.eat(Token::LBracket) {
+            predicates.push(self.or_expr()?);
+            self.expect(Token::RBracket, "']'")?;
+        }
+        Ok(predicates)
+    }
+
+    fn primary_expr(&mut self) -> PResult<'a> {
+        let raw = self.here();
+        match self.peek() {
+            Some(Token::Variable(name)) => Err(SelectorError::new(
+                format!("Variable '${name}' is not defined"),
+                raw,
+            )),
+            Some(Token::LParen) => {
+                self.pos += 1;
+                let expr = self.or_expr()?;
+                self.expect(Token::RParen, "')'")?;
+                Ok(expr)
+            }
+            Some(Token::Literal(text)) => {
+                self.pos += 1;
+                Ok(Expr::Literal(text.to_string()))
+            }
+            Some(Token::Number(n)) => {
+                self.pos += 1;
+                Ok(Expr::Number(n))
+            }
+            Some(Token::FunctionName(name)) => {
+                self.pos += 1;
+                self.function_call(name, raw)
+            }
+            _ => Err(self.unexpected("an expression")),
+        }
+    }
+
+    fn function_call(&mut self, name: &str, raw: &'a str) -> PResult<'a> {
+        let &(_, function, min, max) = FUNCTIONS
+            .iter()
+            .find(|&&(f, ..)| f == name)
+            .ok_or_else(|| SelectorError::new(format!("Unknown function '{name}'"), raw))?;
+        self.expect(Token::LParen, "'('")?;
+        let mut args = Vec::new();
+        if !self.eat(Token::RParen) {
+            loop {
+                args.push(if function.takes_node_set() {
+                    self.node_set(Self::or_expr)?
+                } else {
+                    self.or_expr()?
+                });
+                if self.eat(Token::RParen) {
+                    break;
+                }
+                self.expect(Token::Comma, "',' or ')'")?;
+            }
+        }
+        if args.len() < min || args.len() > max {
+            return Err(SelectorError::new(
+                format!("Wrong number of arguments to '{name}': {}", args.len()),
+                raw,
+            ));
+        }
+        Ok(Expr::Call(function, args))
+    }
+}
+
+type PResult<'a> = Result<Expr, SelectorError<'a>>;
+type MakeBinary = fn(Box<Expr>, Box<Expr>) -> Expr;
+
+/// `//` abbreviates `/descendant-or-self::node()/`.
+fn descendant_or_self() -> Step {
+    Step {
+        axis: Axis::DescendantOrSelf,
+        test: NodeTest::Node,
+        predicates: Vec::new(),
+    }
+}
+
+/// Parse an XPath 1.0 expression.
+pub(crate) fn parse(s: &str) -> Result<Expr, SelectorError<'_>> {
+    let tokens = lex(s)?;
+    if tokens.is_empty() {
+        return Err(SelectorError::new("XPath expression must not be empty", s));
+    }
+    let mut parser = Parser {
+        source: s,
+        tokens,
+        pos: 0,
+    };
+    let expr = parser.or_expr()?;
+    if parser.pos < parser.tokens.len() {
+        return Err(parser.unexpected("end of expression"));
+    }
+    Ok(expr)
+}